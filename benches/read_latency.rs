@@ -0,0 +1,125 @@
+//! Latency of reads through the downloader, against an HTTP server on localhost serving range
+//! requests. Reads of bytes already downloaded are answered from memory, the first read of a video
+//! waits on its first fetch.
+
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rusty_pipe_cli::downloader::{DownloaderInput, DownloaderS};
+use rusty_pipe_cli::StreamResponse;
+
+/// Size of the file served.
+const FILE_LEN: usize = 4 * 1024 * 1024;
+/// Bytes asked for by a read, what the decoder asks for.
+const READ_LEN: usize = 32 * 1024;
+
+fn byte_at(pos: usize) -> u8 {
+    (pos % 251) as u8
+}
+
+/// Serves `FILE_LEN` bytes at every path, honouring `Range: bytes=<start>-[<end>]`.
+fn serve(listener: TcpListener) {
+    for stream in listener.incoming().flatten() {
+        std::thread::spawn(move || {
+            // The client hangs up once it read what it wanted.
+            let _ = respond(stream);
+        });
+    }
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut range = None;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let lower = line.to_lowercase();
+        if let Some(value) = lower.strip_prefix("range: bytes=") {
+            let (start, end) = value.trim().split_once('-').unwrap_or((value.trim(), ""));
+            let start = start.parse::<usize>().unwrap_or(0);
+            let end = end.parse::<usize>().map_or(FILE_LEN, |end| end + 1);
+            range = Some((start, end.min(FILE_LEN)));
+        }
+    }
+    let (status, start, end) = match range {
+        Some((start, _)) if start >= FILE_LEN => ("416 Range Not Satisfiable", 0, 0),
+        Some((start, end)) => ("206 Partial Content", start, end),
+        None => ("200 OK", 0, FILE_LEN),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        end - start
+    )?;
+    let body = (start..end).map(byte_at).collect::<Vec<_>>();
+    stream.write_all(&body)
+}
+
+/// Starts the fixture server and a downloader, returning the server's address and the sender of
+/// the downloader.
+fn setup() -> (String, crossbeam_channel::Sender<DownloaderInput>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Cant bind fixture server");
+    let address = listener.local_addr().expect("No address").to_string();
+    std::thread::spawn(move || serve(listener));
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let (responder, _replies) = crossbeam_channel::unbounded();
+    let mut downloader = DownloaderS::new(receiver, responder);
+    std::thread::spawn(move || async_std::task::block_on(downloader.run()));
+    (address, sender)
+}
+
+fn open(
+    address: &str,
+    video_id: &str,
+    sender: &crossbeam_channel::Sender<DownloaderInput>,
+) -> StreamResponse {
+    StreamResponse::new(
+        format!("http://{}/videoplayback?id={}", address, video_id),
+        video_id.to_string(),
+        None,
+        Some(FILE_LEN),
+        sender.clone(),
+    )
+}
+
+fn read_latency(c: &mut Criterion) {
+    let (address, sender) = setup();
+    let mut group = c.benchmark_group("read_latency");
+
+    // A video downloaded to the end, reads are answered from memory.
+    let mut stream = open(&address, "downloaded", &sender);
+    let mut all = vec![];
+    stream.read_to_end(&mut all).expect("Cant read fixture");
+    assert_eq!(all.len(), FILE_LEN);
+    let mut buf = vec![0; READ_LEN];
+    let mut pos = 0;
+    group.bench_function("downloaded", |b| {
+        b.iter(|| {
+            pos = (pos + 7 * READ_LEN) % (FILE_LEN - READ_LEN);
+            stream.seek(SeekFrom::Start(pos as u64)).expect("Cant seek");
+            let read = stream.read(&mut buf).expect("Cant read");
+            assert_eq!(buf[0], byte_at(pos));
+            read
+        })
+    });
+
+    // The first read of a new video, answered once its first fetch brings the bytes in.
+    let mut videos = 0;
+    group.bench_function("first_read", |b| {
+        b.iter(|| {
+            videos += 1;
+            let video_id = format!("video{}", videos);
+            let mut stream = open(&address, &video_id, &sender);
+            stream.read(&mut buf).expect("Cant read")
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, read_latency);
+criterion_main!(benches);
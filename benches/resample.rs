@@ -0,0 +1,79 @@
+//! Resampling a second of 44.1 kHz packets to 48 kHz, with a best quality converter created for
+//! every packet as the output used to, and with the streaming converter at every quality.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[allow(dead_code)]
+#[path = "../src/dsp/resample.rs"]
+mod resample;
+
+use resample::{ResampleQuality, Resampler};
+
+const FROM: u32 = 44100;
+const TO: u32 = 48000;
+const CHANNELS: usize = 2;
+/// Frames of a packet, those of an AAC frame.
+const PACKET_FRAMES: usize = 1024;
+const PACKETS: usize = 43;
+
+/// Interleaved packets of a 440 Hz tone.
+fn packets() -> Vec<Vec<f32>> {
+    (0..PACKETS)
+        .map(|packet| {
+            (0..PACKET_FRAMES * CHANNELS)
+                .map(|i| {
+                    let frame = (packet * PACKET_FRAMES + i / CHANNELS) as f32;
+                    (frame * 440.0 * 2.0 * std::f32::consts::PI / FROM as f32).sin() * 0.5
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn per_packet(packets: &[Vec<f32>]) -> usize {
+    packets
+        .iter()
+        .map(|packet| {
+            let converter = samplerate::Samplerate::new(
+                samplerate::ConverterType::SincBestQuality,
+                FROM,
+                TO,
+                CHANNELS,
+            )
+            .expect("Cant create converter");
+            converter.process_last(packet).expect("Cant convert").len()
+        })
+        .sum()
+}
+
+fn streaming(quality: ResampleQuality, packets: &[Vec<f32>]) -> usize {
+    let mut resampler = Resampler::new(quality, FROM, TO, CHANNELS);
+    let converted = packets
+        .iter()
+        .map(|packet| resampler.process(packet.clone()).len())
+        .sum::<usize>();
+    converted + resampler.flush().len()
+}
+
+fn resample(c: &mut Criterion) {
+    let packets = packets();
+    let mut group = c.benchmark_group("resample");
+    group.bench_function("per_packet", |b| b.iter(|| per_packet(&packets)));
+    for quality in [
+        ResampleQuality::Fastest,
+        ResampleQuality::Medium,
+        ResampleQuality::Best,
+    ]
+    .iter()
+    {
+        group.bench_with_input(
+            BenchmarkId::new("streaming", format!("{:?}", quality)),
+            quality,
+            |b, quality| b.iter(|| streaming(*quality, &packets)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, resample);
+criterion_main!(benches);
@@ -0,0 +1,345 @@
+//! The audio cache: tracks downloaded to the RustyPipe audio directory, kept within the limits
+//! of the configuration by evicting the tracks played least recently.
+//!
+//! Besides the cached files the directory holds `cache.json`, recording when each track was last
+//! played and which tracks are pinned. Pinned tracks and the track playing are never evicted.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Extension of the cached audio files.
+const AUDIO_EXTENSION: &str = "m4a";
+/// Extensions of the files kept next to a cached track.
+const SIDECAR_EXTENSIONS: [&str; 2] = ["ranges", "loudness"];
+
+lazy_static::lazy_static! {
+    /// Held while `cache.json` is read, changed and written back. The player, the downloader and
+    /// the server all update it, and would otherwise overwrite each other's changes.
+    static ref INDEX_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Limits of the cache, from the configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct CacheLimits {
+    /// Largest total size, as a number of bytes with an optional `KB`, `MB` or `GB` suffix.
+    pub max_size: Option<String>,
+    /// Tracks not played for longer than this many days are evicted.
+    pub max_age_days: Option<u64>,
+}
+
+impl CacheLimits {
+    pub fn max_bytes(&self) -> Option<u64> {
+        let size = self.max_size.as_deref()?;
+        let bytes = parse_size(size);
+        if bytes.is_none() {
+            log::warn!("Cant parse cache size {}", size);
+        }
+        bytes
+    }
+}
+
+/// Parses sizes such as `500MB`, `1.5 GB` or `1048576`.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_ascii_uppercase();
+    let (number, unit) = match size.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => size.split_at(i),
+        None => (size.as_str(), ""),
+    };
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    let number = number.trim().parse::<f64>().ok()?;
+    if number < 0.0 {
+        return None;
+    }
+    Some((number * multiplier as f64) as u64)
+}
+
+/// What `cache.json` records about a track.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct EntryMeta {
+    /// When the track was last played, in seconds since the epoch.
+    last_access: u64,
+    pinned: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub video_id: String,
+    /// Size of the audio file and the files kept next to it, in bytes.
+    pub size: u64,
+    /// When the track was last played, in seconds since the epoch.
+    pub last_access: u64,
+    pub pinned: bool,
+    /// False while the download is partial.
+    pub complete: bool,
+}
+
+pub struct CacheManager {
+    dir: PathBuf,
+}
+
+impl CacheManager {
+    /// The cache in the user's audio directory, created if needed.
+    pub fn open() -> Option<Self> {
+        let dir = dirs::audio_dir()?.join("RustyPipe");
+        if let Err(err) = std::fs::create_dir_all(&dir) {
+            log::error!("Cant create cache dir {:#?}", err);
+            return None;
+        }
+        Some(Self { dir })
+    }
+
+    /// Path the audio of `video_id` is cached at.
+    pub fn path_for(&self, video_id: &str) -> PathBuf {
+        self.dir.join(video_id).with_extension(AUDIO_EXTENSION)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join("cache.json")
+    }
+
+    fn load_index(&self) -> HashMap<String, EntryMeta> {
+        std::fs::read(self.index_path())
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Loads the index, lets `update` change it and saves it if `update` returns true.
+    fn update_index(&self, update: impl FnOnce(&mut HashMap<String, EntryMeta>) -> bool) {
+        let _guard = INDEX_LOCK.lock().expect("Cant lock cache index");
+        let mut index = self.load_index();
+        if update(&mut index) {
+            self.save_index(&index);
+        }
+    }
+
+    fn save_index(&self, index: &HashMap<String, EntryMeta>) {
+        match serde_json::to_vec(index) {
+            Ok(data) => {
+                if let Err(err) = std::fs::write(self.index_path(), data) {
+                    log::warn!("Cant save cache index {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant serialize cache index {:#?}", err),
+        }
+    }
+
+    /// The tracks in the cache, played most recently first.
+    pub fn entries(&self) -> Vec<CacheEntry> {
+        let index = self.load_index();
+        let files = match std::fs::read_dir(&self.dir) {
+            Ok(files) => files,
+            Err(err) => {
+                log::warn!("Cant read cache dir {:#?}", err);
+                return vec![];
+            }
+        };
+        let mut entries = files
+            .filter_map(|file| file.ok().map(|file| file.path()))
+            .filter(|path| path.extension().and_then(|e| e.to_str()) == Some(AUDIO_EXTENSION))
+            .filter_map(|path| {
+                let video_id = path.file_stem()?.to_str()?.to_string();
+                let meta = index.get(&video_id).cloned().unwrap_or_else(|| EntryMeta {
+                    last_access: modified_secs(&path),
+                    pinned: false,
+                });
+                let sidecars = SIDECAR_EXTENSIONS
+                    .iter()
+                    .map(|ext| path.with_extension(ext));
+                Some(CacheEntry {
+                    size: std::iter::once(path.clone())
+                        .chain(sidecars)
+                        .filter_map(|p| std::fs::metadata(p).ok())
+                        .map(|m| m.len())
+                        .sum(),
+                    complete: !path.with_extension("ranges").exists(),
+                    last_access: meta.last_access,
+                    pinned: meta.pinned,
+                    video_id,
+                })
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| b.last_access.cmp(&a.last_access));
+        entries
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.entries().iter().map(|e| e.size).sum()
+    }
+
+    /// Records that `video_id` is being played.
+    pub fn touch(&self, video_id: &str) {
+        self.update_index(|index| {
+            index.entry(video_id.to_string()).or_default().last_access = now_secs();
+            true
+        });
+    }
+
+    /// Pins or unpins `video_id`. Returns false if it is not cached.
+    pub fn pin(&self, video_id: &str, pinned: bool) -> bool {
+        let path = self.path_for(video_id);
+        if !path.exists() {
+            return false;
+        }
+        self.update_index(|index| {
+            let meta = index
+                .entry(video_id.to_string())
+                .or_insert_with(|| EntryMeta {
+                    last_access: modified_secs(&path),
+                    pinned,
+                });
+            meta.pinned = pinned;
+            true
+        });
+        true
+    }
+
+    /// Deletes `video_id` from the cache. Returns false if it was not cached.
+    pub fn remove(&self, video_id: &str) -> bool {
+        let path = self.path_for(video_id);
+        let existed = path.exists();
+        for ext in SIDECAR_EXTENSIONS.iter() {
+            let _ = std::fs::remove_file(path.with_extension(ext));
+        }
+        if let Err(err) = std::fs::remove_file(&path) {
+            if existed {
+                log::warn!("Cant remove cached file {:?} {:#?}", path, err);
+            }
+        }
+        self.update_index(|index| index.remove(video_id).is_some());
+        existed
+    }
+
+    /// Deletes every track that is not pinned, except the tracks in `keep`, those playing or
+    /// queued. Returns how many were deleted.
+    pub fn clear(&self, keep: &[String]) -> usize {
+        self.entries()
+            .into_iter()
+            .filter(|entry| !entry.pinned && !keep.contains(&entry.video_id))
+            .filter(|entry| self.remove(&entry.video_id))
+            .count()
+    }
+
+    /// Evicts tracks older than the age limit, then the tracks played least recently until the
+    /// cache fits the size limit. Pinned tracks and the tracks in `keep`, those playing or
+    /// downloading, stay. Returns the evicted tracks.
+    pub fn enforce(&self, limits: &CacheLimits, keep: &[String]) -> Vec<String> {
+        let mut entries = self.entries();
+        let mut evicted = vec![];
+        let evictable = |entry: &CacheEntry| !entry.pinned && !keep.contains(&entry.video_id);
+
+        if let Some(days) = limits.max_age_days {
+            let oldest = now_secs().saturating_sub(days * 24 * 60 * 60);
+            for entry in entries
+                .iter()
+                .filter(|e| evictable(e) && e.last_access < oldest)
+            {
+                evicted.push(entry.video_id.clone());
+            }
+        }
+        entries.retain(|entry| !evicted.contains(&entry.video_id));
+
+        if let Some(max_bytes) = limits.max_bytes() {
+            let mut total: u64 = entries.iter().map(|e| e.size).sum();
+            // Entries are sorted most recent first.
+            for entry in entries.iter().rev() {
+                if total <= max_bytes {
+                    break;
+                }
+                if evictable(entry) {
+                    total -= entry.size;
+                    evicted.push(entry.video_id.clone());
+                }
+            }
+        }
+
+        for video_id in evicted.iter() {
+            log::info!("Evicting {} from the cache", video_id);
+            self.remove(video_id);
+        }
+        evicted
+    }
+}
+
+/// Path the audio of `video_id` is cached at, creating the cache directory if needed. The cache is
+/// brought back within its limits by the downloader, when a download completes.
+pub fn cache_path(video_id: &str) -> Option<String> {
+    let cache = CacheManager::open()?;
+    cache
+        .path_for(video_id)
+        .to_str()
+        .map(|path| path.to_string())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// When `path` was last modified, in seconds since the epoch, for tracks cached before the index
+/// recorded them.
+fn modified_secs(path: &Path) -> u64 {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Runs `cache <command>` from the command line: `list`, `clear`, `prune`, `pin <id>`,
+/// `unpin <id>` or `remove <id>`.
+pub fn run_command(args: &[String]) -> Result<(), String> {
+    let cache = CacheManager::open().ok_or("No audio directory for the cache")?;
+    let id = || args.get(1).map(|id| id.as_str()).ok_or("Missing video id");
+    match args.first().map(|c| c.as_str()).unwrap_or("list") {
+        "list" => {
+            let entries = cache.entries();
+            for entry in entries.iter() {
+                println!(
+                    "{} {:>8.1} MB{}{}",
+                    entry.video_id,
+                    entry.size as f64 / (1 << 20) as f64,
+                    if entry.pinned { " pinned" } else { "" },
+                    if entry.complete { "" } else { " partial" },
+                );
+            }
+            let total: u64 = entries.iter().map(|e| e.size).sum();
+            println!(
+                "{} tracks, {:.1} MB",
+                entries.len(),
+                total as f64 / (1 << 20) as f64
+            );
+        }
+        "clear" => println!("Removed {} tracks", cache.clear(&[])),
+        "prune" => {
+            let evicted = cache.enforce(&crate::config::Config::load().cache, &[]);
+            println!("Removed {} tracks", evicted.len());
+        }
+        "pin" | "unpin" => {
+            if !cache.pin(id()?, args[0] == "pin") {
+                return Err(format!("{} is not cached", args[1]));
+            }
+        }
+        "remove" => {
+            if !cache.remove(id()?) {
+                return Err(format!("{} is not cached", args[1]));
+            }
+        }
+        command => return Err(format!("Unknown cache command {}", command)),
+    }
+    Ok(())
+}
@@ -5,13 +5,18 @@ use futures::{
     channel::mpsc::{Receiver, Sender},
     SinkExt, StreamExt,
 };
-use rusty_pipe::youtube_extractor::{
-    search_extractor::YTSearchItem, stream_extractor::YTStreamExtractor,
-};
+use rusty_pipe::youtube_extractor::search_extractor::YTSearchItem;
 use unicode_width::UnicodeWidthStr;
 
 use crate::{
-    server::schema::{PlayOptions, PlayerMessage, PlayerStatus, ToPlayerMessages},
+    cache,
+    config::Config,
+    downloader::refresh,
+    server::schema::{
+        AutoplayOptions, BufferingStatus, EqualizerStatus, PlayOptions, PlayerError, PlayerMessage,
+        PlayerState, PlayerStatus, QueueStatus, RepeatMode, StateStatus, ToPlayerMessages,
+        TrackInfo,
+    },
     yt_downloader::YTDownloader,
 };
 
@@ -47,6 +52,22 @@ struct App {
     selected_result: Option<YTSearchItem>,
 
     player_status: Option<PlayerStatus>,
+
+    queue: Option<QueueStatus>,
+
+    equalizer: Option<EqualizerStatus>,
+
+    state: Option<StateStatus>,
+
+    track: Option<TrackInfo>,
+
+    buffering: Option<BufferingStatus>,
+
+    /// Last player error, cleared when the next track starts.
+    error: Option<PlayerError>,
+
+    /// Autoplay options last sent to the player.
+    autoplay: AutoplayOptions,
 }
 
 impl Default for App {
@@ -57,6 +78,13 @@ impl Default for App {
             results: Vec::new(),
             selected_result: None,
             player_status: None,
+            queue: None,
+            equalizer: None,
+            state: None,
+            track: None,
+            buffering: None,
+            error: None,
+            autoplay: AutoplayOptions::default(),
         }
     }
 }
@@ -106,7 +134,53 @@ pub async fn run_tui_pipe(
                             Span::styled("q", Style::default().add_modifier(Modifier::BOLD)),
                             Span::raw(" to exit, "),
                             Span::styled("e", Style::default().add_modifier(Modifier::BOLD)),
-                            Span::raw(" to start editing."),
+                            Span::raw(" to start editing, "),
+                            Span::styled("a", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("i", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" to queue last/next, "),
+                            Span::styled("n", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("p", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" to skip, "),
+                            Span::styled("←", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("→", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" to seek, "),
+                            Span::styled("c", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" to clear queue, "),
+                            Span::styled("s", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("r", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" for shuffle/repeat, "),
+                            Span::styled("o", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" for autoplay, "),
+                            Span::styled("+", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("-", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("m", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" for volume/mute, "),
+                            Span::styled("[", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("]", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" for speed, "),
+                            Span::styled("g", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("G", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" for EQ preset/toggle, "),
+                            Span::styled(",", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled(".", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("M", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" for balance/mono, "),
+                            Span::styled("b", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw("/"),
+                            Span::styled("B", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" for metered/bandwidth cap, "),
+                            Span::styled("d", Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(" to switch output device."),
                         ],
                         Style::default().add_modifier(Modifier::DIM),
                     ),
@@ -178,23 +252,73 @@ pub async fn run_tui_pipe(
                     .collect();
                 let messages = List::new(messages)
                     .block(Block::default().borders(Borders::ALL).title("Results"));
-                f.render_widget(messages, chunks[2]);
+                let content_row = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+                    .split(chunks[2]);
+                f.render_widget(messages, content_row[0]);
+
+                let queue_items: Vec<ListItem> = app
+                    .queue
+                    .as_ref()
+                    .map(|queue| {
+                        queue
+                            .items
+                            .iter()
+                            .enumerate()
+                            .map(|(i, item)| {
+                                let mut list_item =
+                                    ListItem::new(format!("{}: {}", i, item.video_id));
+                                if queue.current == Some(i as i32) {
+                                    list_item = list_item
+                                        .style(Style::default().add_modifier(Modifier::BOLD));
+                                }
+                                list_item
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let queue_list = List::new(queue_items)
+                    .block(Block::default().borders(Borders::ALL).title("Queue"));
+                let side_column = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(1), Constraint::Length(3)])
+                    .split(content_row[1]);
+                f.render_widget(queue_list, side_column[0]);
+
+                let (eq_title, eq_text) = match &app.equalizer {
+                    Some(eq) => (
+                        format!(
+                            "EQ {} {}",
+                            eq.preset.as_deref().unwrap_or("custom"),
+                            if eq.enabled { "on" } else { "off" }
+                        ),
+                        vec![Spans::from(Span::raw(
+                            eq.bands.iter().map(|b| eq_bar(b.gain)).collect::<String>(),
+                        ))],
+                    ),
+                    None => (format!("EQ"), vec![]),
+                };
+                let eq_panel = Paragraph::new(eq_text)
+                    .block(Block::default().borders(Borders::ALL).title(eq_title));
+                f.render_widget(eq_panel, side_column[1]);
 
                 let player_row = Layout::default()
                     .direction(Direction::Horizontal)
                     .constraints([
                         Constraint::Length(15),
                         Constraint::Min(1),
-                        Constraint::Length(15),
+                        Constraint::Length(32),
                     ])
                     .split(chunks[3]);
+                let label = status_label(&app);
                 let progress = Gauge::default()
                     .block(Block::default().borders(Borders::empty()))
                     .gauge_style(Style::default().fg(Color::White).bg(Color::Black))
-                    .label("")
+                    .label(label.as_str())
                     .percent({
                         if let Some(data) = &app.player_status {
-                            ((data.current_status.unwrap_or(0) as f32
+                            ((data.position.unwrap_or(0.0) as f32
                                 / data.total_time.unwrap_or(1) as f32)
                                 * 100.0)
                                 .clamp(0.0, 100.0) as u16
@@ -225,7 +349,29 @@ pub async fn run_tui_pipe(
                         let secs = dur % 60;
                         let mins = dur / 60;
                         let hours = dur / (60 * 60);
-                        format!("{}:{:0>2}:{:0>2}", hours, mins, secs)
+                        format!(
+                            "{}{} {}{}{} {}:{:0>2}:{:0>2}",
+                            if (pd.speed - 1.0).abs() > f64::EPSILON {
+                                format!("{}x ", pd.speed)
+                            } else {
+                                format!("")
+                            },
+                            if pd.muted {
+                                format!("🔇")
+                            } else {
+                                format!("🔊{}%", pd.volume)
+                            },
+                            if pd.autoplay { "📻" } else { "" },
+                            if pd.shuffle { "🔀" } else { "" },
+                            match pd.repeat {
+                                RepeatMode::Off => "",
+                                RepeatMode::One => "🔂",
+                                RepeatMode::All => "🔁",
+                            },
+                            hours,
+                            mins,
+                            secs
+                        )
                     } else {
                         format!("")
                     }
@@ -253,6 +399,31 @@ pub async fn run_tui_pipe(
                     PlayerMessage::Status(status) => {
                         app.player_status = Some(status);
                     }
+                    PlayerMessage::Queue(queue) => {
+                        app.queue = Some(queue);
+                    }
+                    PlayerMessage::Equalizer(equalizer) => {
+                        app.equalizer = Some(equalizer);
+                    }
+                    PlayerMessage::Seek(status) => {
+                        if let Some(error) = status.error {
+                            log::warn!("Seek failed {}", error);
+                        }
+                    }
+                    PlayerMessage::State(state) => {
+                        app.state = Some(state);
+                    }
+                    PlayerMessage::TrackChanged(track) => {
+                        app.track = Some(track);
+                        app.error = None;
+                    }
+                    PlayerMessage::Buffering(buffering) => {
+                        app.buffering = Some(buffering);
+                    }
+                    PlayerMessage::Error(error) => {
+                        log::warn!("Player error {:?} {}", error.kind, error.message);
+                        app.error = Some(error);
+                    }
                 },
                 IMsg::CrossTermEvent(event) => match event {
                     Ok(event) => match event {
@@ -276,50 +447,182 @@ pub async fn run_tui_pipe(
                                     }
                                 }
                                 KeyCode::Enter => {
-                                    if let Some(item) = &app.selected_result {
-                                        if let YTSearchItem::StreamInfoItem(video) = item {
-                                            if let Ok(video_id) = video.video_id() {
-                                                if let Ok((url, size)) = play_video(&video_id).await
-                                                {
-                                                    let mut cache_dir = dirs::audio_dir();
-                                                    let mut path = None;
-                                                    if let Some(dir) = &mut cache_dir {
-                                                        dir.push("RustyPipe");
-
-                                                        let res =
-                                                            async_std::fs::create_dir_all(&dir)
-                                                                .await;
-
-                                                        dir.push(format!("{}", video_id));
-                                                        dir.set_extension("m4a");
-                                                        match res {
-                                                            Ok(_) => {
-                                                                path = dir
-                                                                    .to_str()
-                                                                    .to_owned()
-                                                                    .map(|f| f.to_string());
-                                                            }
-                                                            Err(err) => {
-                                                                log::error!(
-                                                                    "Cant create cache dir {:#?}",
-                                                                    err
-                                                                )
-                                                            }
-                                                        }
-                                                    }
-                                                    msg_sender
-                                                        .send(ToPlayerMessages::Play(PlayOptions {
-                                                            url,
-                                                            length: size,
-                                                            video_id,
-                                                            file_path: path,
-                                                        }))
-                                                        .await;
-                                                }
-                                            }
+                                    if let Some(options) = selected_play_options(&app).await {
+                                        msg_sender.send(ToPlayerMessages::Play(options)).await;
+                                    }
+                                }
+                                KeyCode::Char('a') => {
+                                    if let Some(options) = selected_play_options(&app).await {
+                                        msg_sender.send(ToPlayerMessages::Enqueue(options)).await;
+                                    }
+                                }
+                                KeyCode::Char('i') => {
+                                    if let Some(options) = selected_play_options(&app).await {
+                                        msg_sender.send(ToPlayerMessages::PlayNext(options)).await;
+                                    }
+                                }
+                                KeyCode::Left => {
+                                    msg_sender.send(ToPlayerMessages::Seek(-10)).await;
+                                }
+                                KeyCode::Right => {
+                                    msg_sender.send(ToPlayerMessages::Seek(10)).await;
+                                }
+                                KeyCode::Char('n') => {
+                                    msg_sender.send(ToPlayerMessages::Next).await;
+                                }
+                                KeyCode::Char('p') => {
+                                    msg_sender.send(ToPlayerMessages::Previous).await;
+                                }
+                                KeyCode::Char('c') => {
+                                    msg_sender.send(ToPlayerMessages::ClearQueue).await;
+                                }
+                                KeyCode::Char('s') => {
+                                    let shuffle = app
+                                        .player_status
+                                        .as_ref()
+                                        .map(|status| status.shuffle)
+                                        .unwrap_or(false);
+                                    msg_sender
+                                        .send(ToPlayerMessages::SetShuffle(!shuffle, None))
+                                        .await;
+                                }
+                                KeyCode::Char('r') => {
+                                    let repeat = match app.player_status.as_ref().map(|s| s.repeat)
+                                    {
+                                        Some(RepeatMode::Off) | None => RepeatMode::All,
+                                        Some(RepeatMode::All) => RepeatMode::One,
+                                        Some(RepeatMode::One) => RepeatMode::Off,
+                                    };
+                                    msg_sender.send(ToPlayerMessages::SetRepeat(repeat)).await;
+                                }
+                                KeyCode::Char('+') | KeyCode::Char('=') => {
+                                    if let Some(status) = &app.player_status {
+                                        let volume = (status.volume + 5).min(150) as u8;
+                                        msg_sender.send(ToPlayerMessages::SetVolume(volume)).await;
+                                    }
+                                }
+                                KeyCode::Char('-') => {
+                                    if let Some(status) = &app.player_status {
+                                        let volume = (status.volume - 5).max(0) as u8;
+                                        msg_sender.send(ToPlayerMessages::SetVolume(volume)).await;
+                                    }
+                                }
+                                KeyCode::Char(']') => {
+                                    if let Some(status) = &app.player_status {
+                                        let speed = (status.speed + 0.25).min(3.0);
+                                        msg_sender.send(ToPlayerMessages::SetSpeed(speed)).await;
+                                    }
+                                }
+                                KeyCode::Char('[') => {
+                                    if let Some(status) = &app.player_status {
+                                        let speed = (status.speed - 0.25).max(0.5);
+                                        msg_sender.send(ToPlayerMessages::SetSpeed(speed)).await;
+                                    }
+                                }
+                                KeyCode::Char(',') | KeyCode::Char('.') => {
+                                    if let Some(status) = &app.player_status {
+                                        let step = if key.code == KeyCode::Char(',') {
+                                            -0.1
+                                        } else {
+                                            0.1
+                                        };
+                                        let balance =
+                                            ((status.balance + step) * 10.0).round() / 10.0;
+                                        let balance = balance.max(-1.0).min(1.0);
+                                        msg_sender
+                                            .send(ToPlayerMessages::SetBalance(balance))
+                                            .await;
+                                    }
+                                }
+                                KeyCode::Char('M') => {
+                                    if let Some(status) = &app.player_status {
+                                        msg_sender
+                                            .send(ToPlayerMessages::SetMono(!status.mono))
+                                            .await;
+                                    }
+                                }
+                                KeyCode::Char('b') => {
+                                    if let Some(status) = &app.player_status {
+                                        msg_sender
+                                            .send(ToPlayerMessages::SetMetered(!status.metered))
+                                            .await;
+                                    }
+                                }
+                                KeyCode::Char('B') => {
+                                    if let Some(status) = &app.player_status {
+                                        // Cycle to the cap after the current one.
+                                        let current = BANDWIDTH_LIMITS
+                                            .iter()
+                                            .position(|limit| *limit == status.bandwidth_limit);
+                                        let next = current.map(|i| i + 1).unwrap_or(0);
+                                        let limit =
+                                            BANDWIDTH_LIMITS.get(next).copied().unwrap_or_default();
+                                        msg_sender
+                                            .send(ToPlayerMessages::SetBandwidthLimit(
+                                                limit,
+                                                status.track_bandwidth_limit,
+                                            ))
+                                            .await;
+                                    }
+                                }
+                                KeyCode::Char('g') => {
+                                    if let Some(eq) = &app.equalizer {
+                                        // Cycle to the preset after the current one.
+                                        let current = eq
+                                            .preset
+                                            .as_ref()
+                                            .and_then(|p| eq.presets.iter().position(|n| n == p));
+                                        let next = current.map(|i| i + 1).unwrap_or(0);
+                                        if let Some(preset) =
+                                            eq.presets.get(next).or_else(|| eq.presets.first())
+                                        {
+                                            msg_sender
+                                                .send(ToPlayerMessages::SetEqualizerPreset(
+                                                    preset.clone(),
+                                                ))
+                                                .await;
                                         }
                                     }
                                 }
+                                KeyCode::Char('G') => {
+                                    if let Some(eq) = &app.equalizer {
+                                        msg_sender
+                                            .send(ToPlayerMessages::EnableEqualizer(!eq.enabled))
+                                            .await;
+                                    }
+                                }
+                                KeyCode::Char('d') => {
+                                    // Cycle through the devices, then back to the default one.
+                                    let devices = crate::output::output_devices();
+                                    let current = app
+                                        .player_status
+                                        .as_ref()
+                                        .and_then(|s| s.output_device.as_ref())
+                                        .and_then(|c| devices.iter().position(|d| d == c));
+                                    let next = match current {
+                                        Some(i) => devices.get(i + 1).cloned(),
+                                        None => devices.first().cloned(),
+                                    };
+                                    msg_sender
+                                        .send(ToPlayerMessages::SetOutputDevice(next))
+                                        .await;
+                                }
+                                KeyCode::Char('m') => {
+                                    if let Some(status) = &app.player_status {
+                                        msg_sender
+                                            .send(ToPlayerMessages::Mute(!status.muted))
+                                            .await;
+                                    }
+                                }
+                                KeyCode::Char('o') => {
+                                    if let Some(status) = &app.player_status {
+                                        app.autoplay.enabled = status.autoplay;
+                                    }
+                                    app.autoplay.enabled = !app.autoplay.enabled;
+                                    msg_sender
+                                        .send(ToPlayerMessages::SetAutoplay(app.autoplay.clone()))
+                                        .await;
+                                }
                                 KeyCode::Down => {
                                     if let Some(selected_item) = &app.selected_result {
                                         let i =
@@ -421,24 +724,70 @@ pub async fn run_tui_pipe(
     crossterm::execute!(io::stdout(), crossterm::terminal::EnterAlternateScreen);
 }
 
-pub async fn play_video(id: &str) -> Result<(String, Option<usize>), anyhow::Error> {
-    let mut stream_extractor = YTStreamExtractor::new(id, YTDownloader {})
-        .await
-        .map_err(|e| anyhow::anyhow!("{:#?}", e))?;
-    let audio_streams = stream_extractor
-        .get_audio_streams().await
-        .map_err(|er| anyhow::anyhow!("Cant get audio streams"))?;
-    let stream_info = audio_streams
-        .iter()
-        .filter(|f| f.mimeType.contains("mp4"))
-        .nth(0)
-        .ok_or(anyhow::anyhow!("No m4a stream found"))?;
-    let url = stream_info
-        .url
-        .clone()
-        .ok_or(anyhow::anyhow!("No url in stream"))?;
-
-    let response = surf::get(&url).send().await.unwrap();
-    let length = response.len();
-    Ok((url, length))
+/// Text shown over the progress bar: what the player is doing, the last error or the title of
+/// the track.
+fn status_label(app: &App) -> String {
+    let state = app.state.as_ref().map(|s| s.state);
+    match state {
+        Some(PlayerState::Loading) => return "Loading…".to_string(),
+        Some(PlayerState::Buffering) => {
+            let video_id = app.state.as_ref().and_then(|s| s.video_id.as_ref());
+            return match &app.buffering {
+                Some(b) if Some(&b.video_id) == video_id => {
+                    format!(
+                        "Buffering {:.0}% ({:.0} KB/s)",
+                        b.percent,
+                        b.bytes_per_sec / 1024.0
+                    )
+                }
+                _ => "Buffering…".to_string(),
+            };
+        }
+        _ => {}
+    }
+    if let Some(error) = &app.error {
+        return format!("{:?} error: {}", error.kind, error.message);
+    }
+    let title = app
+        .track
+        .as_ref()
+        .map(|t| t.title.clone().unwrap_or_else(|| t.video_id.clone()))
+        .unwrap_or_default();
+    match state {
+        Some(PlayerState::Ended) => format!("{} (ended)", title),
+        _ => title,
+    }
+}
+
+/// Bandwidth caps cycled through with `B`, in bytes per second.
+const BANDWIDTH_LIMITS: [Option<u64>; 4] = [None, Some(64 << 10), Some(256 << 10), Some(1 << 20)];
+
+/// Resolves the selected search result into options the player can use, caching the audio under
+/// the user's audio directory.
+async fn selected_play_options(app: &App) -> Option<PlayOptions> {
+    let video = match &app.selected_result {
+        Some(YTSearchItem::StreamInfoItem(video)) => video,
+        _ => return None,
+    };
+    let video_id = video.video_id().ok()?;
+    let metered = match &app.player_status {
+        Some(status) => status.metered,
+        None => Config::load().download.metered,
+    };
+    let (url, size) = refresh::resolve_stream(&video_id, metered).await.ok()?;
+    let path = cache::cache_path(&video_id);
+    Some(PlayOptions {
+        url,
+        length: size,
+        title: video.get_name().ok(),
+        video_id,
+        file_path: path,
+    })
+}
+
+/// A block whose height shows an equalizer band gain between -12 and +12 dB.
+fn eq_bar(gain: f64) -> char {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let level = ((gain + 12.0) / 24.0 * (BARS.len() - 1) as f64).round();
+    BARS[level.max(0.0).min((BARS.len() - 1) as f64) as usize]
 }
@@ -0,0 +1,56 @@
+//! User configuration, read from `config.json` in the RustyPipe config directory.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::CacheLimits,
+    downloader::DownloadOptions,
+    dsp::{equalizer::EqualizerPreset, resample::ResampleQuality},
+    network::NetworkOptions,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Equalizer presets offered next to the built-in ones.
+    pub equalizer_presets: Vec<EqualizerPreset>,
+    /// Name of the audio output device to play on, the default device if not set.
+    pub output_device: Option<String>,
+    /// Where to send the audio, in the form taken by `--sink`. The audio device if not set.
+    pub sink: Option<String>,
+    /// Quality of the conversion when the output runs at another sample rate than the audio.
+    pub resample_quality: ResampleQuality,
+    /// Size and age limits of the audio cache, unbounded if not set.
+    pub cache: CacheLimits,
+    /// How many fetches run at once, how large they are and how far ahead they download.
+    pub download: DownloadOptions,
+    /// Proxy, timeouts, user agent, headers and cookies of every request.
+    pub network: NetworkOptions,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("RustyPipe").join("config.json"))
+    }
+
+    /// Reads the configuration, falling back to the defaults when there is no file or it cant
+    /// be parsed.
+    pub fn load() -> Self {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+        match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(config) => config,
+                Err(err) => {
+                    log::warn!("Cant parse config {:?} {:#?}", path, err);
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        }
+    }
+}
@@ -66,7 +66,7 @@ pub fn decode_file(data: File) -> Box<dyn FormatReader> {
 
     return format;
 }
-pub fn decode(data: StreamResponse) -> Box<dyn FormatReader> {
+pub fn decode(data: StreamResponse) -> Result<Box<dyn FormatReader>, Error> {
     // Get command line arguments.
 
     // Create a media source. Note that the MediaSource trait is automatically implemented for File,
@@ -86,15 +86,14 @@ pub fn decode(data: StreamResponse) -> Box<dyn FormatReader> {
 
     // Probe the media source stream for a format.
     log::info!("Probing stream");
-    let probed = symphonia::default::get_probe()
-        .format(&hint, mss, &format_opts, &metadata_opts)
-        .unwrap();
+    let probed =
+        symphonia::default::get_probe().format(&hint, mss, &format_opts, &metadata_opts)?;
     log::info!("Format probed");
 
     // Get the format reader yielded by the probe operation.
-    let mut format = probed.format;
+    let format = probed.format;
 
-    return format;
+    Ok(format)
     // Get the default track.
     // let track = format.default_track().unwrap();
 
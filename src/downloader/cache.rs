@@ -0,0 +1,151 @@
+//! Files caching downloads, kept on disk while they are still partial.
+//!
+//! Bytes are written to the cache file at their offset as they arrive. Until the file is
+//! complete an index file next to it lists the byte ranges written, so that a download resumed
+//! later only fetches what is missing. A cache file without an index is a complete download.
+
+use std::path::{Path, PathBuf};
+
+use async_std::fs::File;
+use async_std::io::SeekFrom;
+use async_std::prelude::*;
+use async_std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::chunks::ChunkStore;
+
+/// Byte ranges written to a partial cache file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RangeIndex {
+    /// Length of the complete file, 0 if the server did not tell.
+    pub content_length: usize,
+    /// Start and end offsets of the ranges written.
+    pub ranges: Vec<(usize, usize)>,
+}
+
+impl RangeIndex {
+    /// Path of the index kept next to the cache file at `file_path`.
+    pub fn sidecar_path(file_path: &str) -> PathBuf {
+        Path::new(file_path).with_extension("ranges")
+    }
+
+    pub fn load(file_path: &str) -> Option<Self> {
+        let data = std::fs::read(Self::sidecar_path(file_path)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn save(&self, file_path: &str) {
+        match serde_json::to_vec(self) {
+            Ok(data) => {
+                if let Err(err) = std::fs::write(Self::sidecar_path(file_path), data) {
+                    log::warn!("Cant save range index {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant serialize range index {:#?}", err),
+        }
+    }
+
+    /// Removes the index once the cache file is complete.
+    pub fn remove(file_path: &str) {
+        let path = Self::sidecar_path(file_path);
+        if path.exists() {
+            if let Err(err) = std::fs::remove_file(path) {
+                log::warn!("Cant remove range index {:#?}", err);
+            }
+        }
+    }
+}
+
+/// Loads what is cached at `file_path` for a file of `content_length` bytes, 0 if unknown.
+/// Returns the bytes found and whether they are the complete file, or None if there is nothing
+/// usable cached.
+pub async fn load(file_path: &str, content_length: usize) -> Option<(ChunkStore, bool)> {
+    let data = match async_std::fs::read(file_path).await {
+        Ok(data) => data,
+        Err(_) => {
+            log::info!("Not cached {}", file_path);
+            return None;
+        }
+    };
+    if !RangeIndex::sidecar_path(file_path).exists() {
+        if content_length != 0 && data.len() != content_length {
+            log::warn!(
+                "Cached file {} has {} bytes, expected {}",
+                file_path,
+                data.len(),
+                content_length
+            );
+            return None;
+        }
+        return Some((ChunkStore::with_data(data), true));
+    }
+
+    let index = match RangeIndex::load(file_path) {
+        Some(index) => index,
+        None => {
+            log::warn!("Range index of {} is corrupt", file_path);
+            return None;
+        }
+    };
+    if content_length != 0 && index.content_length != content_length {
+        log::warn!(
+            "Cached file {} was for {} bytes, the stream now has {}",
+            file_path,
+            index.content_length,
+            content_length
+        );
+        return None;
+    }
+    let mut store = ChunkStore::new(index.content_length.max(content_length));
+    for (start, end) in index.ranges {
+        // A file cut short keeps whatever it still holds.
+        let end = end.min(data.len());
+        if start < end {
+            store.insert(start, &data[start..end]);
+        }
+    }
+    log::info!(
+        "Resuming {} with {} of {} bytes cached",
+        file_path,
+        store.downloaded(),
+        store.len()
+    );
+    Some((store, false))
+}
+
+/// The cache file of a download, opened on the first write and kept open until it is closed.
+pub struct CacheFile {
+    path: String,
+    file: Mutex<Option<File>>,
+}
+
+impl CacheFile {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Writes `data` at offset `pos`, creating the file if needed.
+    pub async fn write_at(&self, pos: usize, data: &[u8]) -> std::io::Result<()> {
+        let mut file = self.file.lock().await;
+        if file.is_none() {
+            let opened = async_std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(&self.path)
+                .await?;
+            *file = Some(opened);
+        }
+        let file = file.as_mut().expect("Just opened");
+        file.seek(SeekFrom::Start(pos as u64)).await?;
+        file.write_all(data).await?;
+        file.flush().await
+    }
+
+    /// Closes the file, the next write opens it again.
+    pub async fn close(&self) {
+        self.file.lock().await.take();
+    }
+}
@@ -0,0 +1,262 @@
+//! Bytes of a download stored as the ranges fetched so far.
+
+use std::collections::BTreeMap;
+
+/// The parts of a file downloaded so far, as runs of bytes keyed by their offset.
+///
+/// Runs never overlap and runs that touch are merged, so a file downloaded front to back is a
+/// single run and finding the run holding an offset is a lookup in the map.
+#[derive(Debug, Default)]
+pub struct ChunkStore {
+    /// Expected length of the file, grown when bytes are stored past it.
+    len: usize,
+    chunks: BTreeMap<usize, Vec<u8>>,
+    /// Number of bytes stored.
+    downloaded: usize,
+}
+
+impl ChunkStore {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            ..Self::default()
+        }
+    }
+
+    /// A store holding the whole file.
+    pub fn with_data(data: Vec<u8>) -> Self {
+        let mut store = Self::new(data.len());
+        if !data.is_empty() {
+            store.downloaded = data.len();
+            store.chunks.insert(0, data);
+        }
+        store
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn downloaded(&self) -> usize {
+        self.downloaded
+    }
+
+    /// Whether every byte of a file of known length is stored.
+    pub fn is_complete(&self) -> bool {
+        self.len > 0 && self.downloaded >= self.len
+    }
+
+    /// The run holding `pos` and its offset.
+    fn chunk_at(&self, pos: usize) -> Option<(usize, &Vec<u8>)> {
+        self.chunks
+            .range(..=pos)
+            .next_back()
+            .filter(|(start, data)| pos < *start + data.len())
+            .map(|(start, data)| (*start, data))
+    }
+
+    pub fn contains(&self, pos: usize) -> bool {
+        self.chunk_at(pos).is_some()
+    }
+
+    /// Up to `max` bytes stored from `pos` on, stopping at the first byte missing.
+    pub fn read(&self, pos: usize, max: usize) -> &[u8] {
+        match self.chunk_at(pos) {
+            Some((start, data)) => {
+                let from = pos - start;
+                &data[from..(from + max).min(data.len())]
+            }
+            None => &[],
+        }
+    }
+
+    /// Offset of the first run stored after `pos`, where a download from `pos` can stop.
+    pub fn next_stored(&self, pos: usize) -> Option<usize> {
+        self.chunks.range(pos..).next().map(|(start, _)| *start)
+    }
+
+    /// Offset of the first byte missing from `pos` on.
+    pub fn next_missing(&self, pos: usize) -> usize {
+        match self.chunk_at(pos) {
+            Some((start, data)) => start + data.len(),
+            None => pos,
+        }
+    }
+
+    /// Number of bytes stored from `pos` on without a gap.
+    pub fn available(&self, pos: usize) -> usize {
+        self.next_missing(pos) - pos
+    }
+
+    /// The stored runs, as their start and end offsets.
+    pub fn ranges(&self) -> Vec<(usize, usize)> {
+        self.chunks
+            .iter()
+            .map(|(start, data)| (*start, *start + data.len()))
+            .collect()
+    }
+
+    /// The whole file, once it is complete.
+    pub fn contiguous(&self) -> Option<&[u8]> {
+        if !self.is_complete() {
+            return None;
+        }
+        self.chunks.get(&0).map(|data| &data[..])
+    }
+
+    /// Stores `data` at `pos`. Bytes already stored are kept. Returns how many bytes were new.
+    pub fn insert(&mut self, pos: usize, data: &[u8]) -> usize {
+        if data.is_empty() {
+            return 0;
+        }
+        let end = pos + data.len();
+        // Every run overlapping or touching the new bytes is merged with them.
+        let first = self
+            .chunks
+            .range(..=pos)
+            .next_back()
+            .filter(|(start, chunk)| *start + chunk.len() >= pos)
+            .map(|(start, _)| *start)
+            .unwrap_or(pos);
+        let keys = self
+            .chunks
+            .range(first..=end)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+
+        let mut start = pos;
+        let mut merged = vec![];
+        let mut added = 0;
+        for key in keys {
+            let chunk = self.chunks.remove(&key).expect("Key just listed");
+            if merged.is_empty() && key <= pos {
+                start = key;
+                merged = chunk;
+                continue;
+            }
+            // Fill the gap up to this run with the new bytes.
+            let cursor = start + merged.len();
+            if key > cursor {
+                merged.extend_from_slice(&data[cursor - pos..key - pos]);
+                added += key - cursor;
+            }
+            merged.extend(chunk);
+        }
+        let cursor = start + merged.len();
+        if end > cursor {
+            merged.extend_from_slice(&data[cursor - pos..]);
+            added += end - cursor;
+        }
+
+        self.downloaded += added;
+        self.len = self.len.max(start + merged.len());
+        self.chunks.insert(start, merged);
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(value: u8, len: usize) -> Vec<u8> {
+        vec![value; len]
+    }
+
+    #[test]
+    fn insert_adjacent_after_merges() {
+        let mut store = ChunkStore::new(100);
+        assert_eq!(store.insert(0, &bytes(1, 10)), 10);
+        assert_eq!(store.insert(10, &bytes(2, 5)), 5);
+        assert_eq!(store.ranges(), vec![(0, 15)]);
+        assert_eq!(store.downloaded(), 15);
+        assert_eq!(store.read(8, 4), &[1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn insert_adjacent_before_merges() {
+        let mut store = ChunkStore::new(100);
+        store.insert(20, &bytes(1, 10));
+        assert_eq!(store.insert(15, &bytes(2, 5)), 5);
+        assert_eq!(store.ranges(), vec![(15, 30)]);
+        assert_eq!(store.available(15), 15);
+    }
+
+    #[test]
+    fn insert_overlapping_keeps_stored_bytes() {
+        let mut store = ChunkStore::new(100);
+        store.insert(0, &bytes(1, 10));
+        assert_eq!(store.insert(5, &bytes(2, 10)), 5);
+        assert_eq!(store.ranges(), vec![(0, 15)]);
+        assert_eq!(store.downloaded(), 15);
+        assert_eq!(
+            store.read(0, 15),
+            &[1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2][..]
+        );
+    }
+
+    #[test]
+    fn insert_bridging_a_gap_merges_both_runs() {
+        let mut store = ChunkStore::new(100);
+        store.insert(0, &bytes(1, 10));
+        store.insert(20, &bytes(3, 10));
+        assert_eq!(store.insert(5, &bytes(2, 20)), 10);
+        assert_eq!(store.ranges(), vec![(0, 30)]);
+        assert_eq!(store.downloaded(), 30);
+        assert_eq!(store.read(9, 2), &[1, 2]);
+        assert_eq!(store.read(19, 2), &[2, 3]);
+    }
+
+    #[test]
+    fn insert_covering_several_runs() {
+        let mut store = ChunkStore::new(100);
+        store.insert(10, &bytes(1, 10));
+        store.insert(30, &bytes(1, 10));
+        assert_eq!(store.insert(0, &bytes(2, 50)), 30);
+        assert_eq!(store.ranges(), vec![(0, 50)]);
+        assert_eq!(store.downloaded(), 50);
+    }
+
+    #[test]
+    fn insert_of_stored_bytes_adds_nothing() {
+        let mut store = ChunkStore::new(100);
+        store.insert(0, &bytes(1, 20));
+        assert_eq!(store.insert(5, &bytes(2, 10)), 0);
+        assert_eq!(store.insert(5, &[]), 0);
+        assert_eq!(store.ranges(), vec![(0, 20)]);
+        assert_eq!(store.read(5, 1), &[1]);
+    }
+
+    #[test]
+    fn gaps_are_reported() {
+        let mut store = ChunkStore::new(40);
+        store.insert(0, &bytes(1, 10));
+        store.insert(20, &bytes(1, 10));
+        assert!(store.contains(9));
+        assert!(!store.contains(10));
+        assert_eq!(store.next_missing(3), 10);
+        assert_eq!(store.next_missing(12), 12);
+        assert_eq!(store.next_stored(10), Some(20));
+        assert_eq!(store.next_stored(21), None);
+        assert!(store.read(15, 10).is_empty());
+        assert!(!store.is_complete());
+        assert!(store.contiguous().is_none());
+    }
+
+    #[test]
+    fn complete_store_is_contiguous() {
+        let mut store = ChunkStore::new(20);
+        store.insert(10, &bytes(2, 10));
+        store.insert(0, &bytes(1, 10));
+        assert!(store.is_complete());
+        assert_eq!(store.contiguous().map(|data| data.len()), Some(20));
+        assert!(ChunkStore::with_data(bytes(1, 5)).is_complete());
+    }
+
+    #[test]
+    fn length_grows_with_bytes_past_it() {
+        let mut store = ChunkStore::new(10);
+        store.insert(5, &bytes(1, 10));
+        assert_eq!(store.len(), 15);
+    }
+}
@@ -0,0 +1,1187 @@
+use async_std::prelude::*;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub mod cache;
+pub mod chunks;
+pub mod refresh;
+pub mod retry;
+pub mod throttle;
+
+use self::cache::{CacheFile, RangeIndex};
+use self::chunks::ChunkStore;
+use self::retry::Backoff;
+use self::throttle::TokenBucket;
+
+/// New bytes downloaded between two saves of the range index of a partial cache file.
+const INDEX_SAVE_BYTES: usize = 256 * 1024;
+/// Bytes read from the network at a time by a fetch.
+const FETCH_CHUNK: usize = 16 * 1024;
+/// Bytes per second of audio assumed until the player tells, those of 128 kbps audio.
+const DEFAULT_BYTE_RATE: f64 = 16_000.0;
+/// Span over which the download throughput is measured.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(3);
+/// Longest time between two progress reports of a video being downloaded.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the downloader fetches, from the configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct DownloadOptions {
+    /// Fetches running at the same time, over every video.
+    pub workers: usize,
+    /// Bytes fetched by one request.
+    pub chunk_size: usize,
+    /// Seconds of audio downloaded ahead of the position read.
+    pub read_ahead_secs: f64,
+    /// Cap on the bandwidth of all downloads together, in bytes per second.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Cap on the bandwidth of each download, in bytes per second.
+    pub max_track_bytes_per_sec: Option<u64>,
+    /// For metered connections: reads ahead `metered_read_ahead_secs` only and plays the audio
+    /// stream with the lowest bitrate.
+    pub metered: bool,
+    pub metered_read_ahead_secs: f64,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            workers: 3,
+            chunk_size: 256 * 1024,
+            read_ahead_secs: 30.0,
+            max_bytes_per_sec: None,
+            max_track_bytes_per_sec: None,
+            metered: false,
+            metered_read_ahead_secs: 10.0,
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// Seconds of audio downloaded ahead of the position read, with metered mode accounted for.
+    pub fn read_ahead(&self) -> f64 {
+        if self.metered {
+            self.metered_read_ahead_secs.min(self.read_ahead_secs)
+        } else {
+            self.read_ahead_secs
+        }
+    }
+}
+
+/// Owns the downloads and answers the reads of the streams.
+///
+/// The downloader is an actor: it sleeps until a request comes in or a fetch reports data. Reads
+/// of bytes already downloaded are answered right away. The file is split in chunks, each one
+/// fetched by a request of its own. A pool of fetches, tasks reading a chunk from the network,
+/// downloads the chunks read first, then the ones ahead of the position read up to the
+/// read-ahead window. Videos with reads waiting are served first.
+pub struct DownloaderS {
+    inbox: UnboundedReceiver<Message>,
+    /// Handed to the fetches to report back.
+    inbox_sender: UnboundedSender<Message>,
+    responder: crossbeam_channel::Sender<Reply>,
+    /// Streams that receive the replies to their reads instead of `responder`, by stream id, with
+    /// the video they read.
+    subscribers: HashMap<StreamId, (String, crossbeam_channel::Sender<Reply>)>,
+    watchers: Vec<UnboundedSender<DownloadEvent>>,
+    /// Last progress sent to the watchers for every video, in whole percent, and when.
+    reported_progress: HashMap<String, (u32, Instant)>,
+    videos: HashMap<String, Video>,
+    options: DownloadOptions,
+    /// Shared by the fetches of every video, for the bandwidth cap of all downloads.
+    bandwidth: Arc<TokenBucket>,
+    next_fetch: u64,
+}
+
+/// What wakes the downloader up.
+enum Message {
+    Input(DownloaderInput),
+    /// The download of a video is ready to fetch.
+    Started(String, Result<DownloadTask, anyhow::Error>),
+    /// A fetch stored this many new bytes of a video.
+    Data(String, usize),
+    /// The stream URL of a video was resolved again after it expired.
+    Refreshed(String, Result<String, DownloadError>),
+    FetchEnded {
+        video_id: String,
+        fetch: u64,
+        start: usize,
+        end: usize,
+        /// Bytes read from the network.
+        fetched: usize,
+        stopped: bool,
+        /// The server refused the URL, it has to be resolved again.
+        expired: bool,
+        /// Why the fetch failed, once retrying did not help.
+        error: Option<DownloadError>,
+    },
+}
+
+/// A video being downloaded.
+#[derive(Default)]
+struct Video {
+    /// None while the download is starting.
+    task: Option<Arc<DownloadTask>>,
+    /// Reads waiting for bytes not downloaded yet.
+    pending: Vec<IncomingTask>,
+    fetches: Vec<Fetch>,
+    /// Position of the last read, the read-ahead window starts there.
+    playhead: usize,
+    /// Bytes per second of audio, as told by the player.
+    byte_rate: Option<f64>,
+    /// Where the server had nothing more, when the length of the file is not known.
+    eof: Option<usize>,
+    /// Bytes fetched recently and when.
+    throughput: VecDeque<(Instant, usize)>,
+    /// The stream URL expired and is being resolved again, nothing is fetched meanwhile.
+    refreshing: bool,
+    /// The download completed and the cache was brought back within its limits.
+    completed: bool,
+    /// Failed for good, nothing is fetched until the next read.
+    failed: bool,
+}
+
+impl Video {
+    /// End of the file as far as it is known.
+    fn limit(&self) -> usize {
+        match &self.task {
+            Some(task) if task.len > 0 => task.len,
+            _ => self.eof.unwrap_or(usize::MAX),
+        }
+    }
+
+    fn byte_rate(&self) -> f64 {
+        self.byte_rate.unwrap_or(DEFAULT_BYTE_RATE)
+    }
+
+    fn bytes_per_sec(&self) -> f64 {
+        let bytes: usize = self.throughput.iter().map(|(_, bytes)| bytes).sum();
+        bytes as f64 / THROUGHPUT_WINDOW.as_secs_f64()
+    }
+
+    fn fetching(&self, chunk: usize) -> bool {
+        self.fetches
+            .iter()
+            .any(|fetch| fetch.chunk == chunk && !fetch.stop.load(Ordering::Relaxed))
+    }
+
+    /// Chunks to download, most urgent first: those of the reads waiting, then those of the
+    /// read-ahead window.
+    fn wanted_chunks(&self, options: &DownloadOptions) -> Vec<usize> {
+        let size = options.chunk_size.max(FETCH_CHUNK);
+        let mut wanted = self
+            .pending
+            .iter()
+            .map(|read| read.pos / size)
+            .collect::<Vec<_>>();
+        let window = (options.read_ahead().max(0.0) * self.byte_rate()) as usize;
+        let last = self.playhead.saturating_add(window) / size;
+        for chunk in self.playhead / size..=last {
+            if !wanted.contains(&chunk) {
+                wanted.push(chunk);
+            }
+        }
+        let limit = self.limit();
+        wanted.retain(|chunk| chunk * size < limit);
+        wanted
+    }
+}
+
+/// A fetch running for a video.
+struct Fetch {
+    id: u64,
+    /// Index of the chunk fetched.
+    chunk: usize,
+    stop: Arc<AtomicBool>,
+}
+
+impl DownloaderS {
+    pub fn new(
+        task_receiver: crossbeam_channel::Receiver<DownloaderInput>,
+        responder: crossbeam_channel::Sender<Reply>,
+    ) -> Self {
+        let (inbox_sender, inbox) = futures::channel::mpsc::unbounded();
+        let forward = inbox_sender.clone();
+        std::thread::spawn(move || {
+            while let Ok(data) = task_receiver.recv() {
+                if forward.unbounded_send(Message::Input(data)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            inbox,
+            inbox_sender,
+            responder,
+            subscribers: HashMap::new(),
+            watchers: vec![],
+            reported_progress: HashMap::new(),
+            videos: HashMap::new(),
+            options: DownloadOptions::default(),
+            bandwidth: Arc::new(TokenBucket::new(None)),
+            next_fetch: 0,
+        }
+    }
+
+    pub fn with_options(mut self, options: DownloadOptions) -> Self {
+        self.set_options(options);
+        self
+    }
+
+    fn set_options(&mut self, options: DownloadOptions) {
+        self.options = options;
+        self.bandwidth.set_rate(options.max_bytes_per_sec);
+        for task in self.videos.values().filter_map(|video| video.task.as_ref()) {
+            task.bandwidth.set_rate(options.max_track_bytes_per_sec);
+        }
+    }
+
+    pub async fn run(&mut self) {
+        log::info!("Downloader started");
+        while let Some(message) = self.inbox.next().await {
+            match message {
+                Message::Input(input) => self.handle_input(input),
+                Message::Started(video_id, task) => self.started(video_id, task),
+                Message::Data(video_id, bytes) => {
+                    let mut completed = false;
+                    if let Some(video) = self.videos.get_mut(&video_id) {
+                        if !video.completed
+                            && video.task.as_ref().map_or(false, |t| t.is_complete())
+                        {
+                            video.completed = true;
+                            completed = true;
+                        }
+                        let now = Instant::now();
+                        video.throughput.push_back((now, bytes));
+                        while let Some((at, _)) = video.throughput.front() {
+                            if now.duration_since(*at) <= THROUGHPUT_WINDOW {
+                                break;
+                            }
+                            video.throughput.pop_front();
+                        }
+                    }
+                    if completed {
+                        self.enforce_cache();
+                    }
+                    self.answer_pending(&video_id);
+                    self.report_progress();
+                }
+                Message::Refreshed(video_id, url) => self.refreshed(video_id, url),
+                Message::FetchEnded {
+                    video_id,
+                    fetch,
+                    start,
+                    end,
+                    fetched,
+                    stopped,
+                    expired,
+                    error,
+                } => {
+                    if expired {
+                        self.refresh(&video_id);
+                    }
+                    self.fetch_ended(video_id, fetch, start..end, fetched, stopped, error)
+                }
+            }
+        }
+        log::info!("Downloader stopped");
+    }
+
+    fn handle_input(&mut self, input: DownloaderInput) {
+        match input {
+            DownloaderInput::DownloadTask(task) => self.request(task),
+            DownloaderInput::Subscribe(stream, video_id, sender) => {
+                self.subscribers.insert(stream, (video_id, sender));
+            }
+            DownloaderInput::Watch(watcher) => self.watchers.push(watcher),
+            DownloaderInput::ByteRate(id, rate) => {
+                if let Some(video) = self.videos.get_mut(&id) {
+                    video.byte_rate = Some(rate).filter(|rate| *rate > 0.0);
+                }
+                self.schedule();
+            }
+            DownloaderInput::SetOptions(options) => {
+                log::info!("Download options changed {:?}", options);
+                self.set_options(options);
+                // A smaller read-ahead window stops the fetches past it.
+                self.schedule();
+            }
+            DownloaderInput::RemoveDownload(stream) => self.remove_stream(stream),
+        }
+    }
+
+    /// Forgets a closed stream, and the download of its video unless another stream reads it, as
+    /// when a preload is closed after its track started playing.
+    fn remove_stream(&mut self, stream: StreamId) {
+        let id = match self.subscribers.remove(&stream) {
+            Some((video_id, _)) => video_id,
+            None => return,
+        };
+        if self
+            .subscribers
+            .values()
+            .any(|(video_id, _)| *video_id == id)
+        {
+            if let Some(video) = self.videos.get_mut(&id) {
+                video.pending.retain(|read| read.stream != stream);
+            }
+            self.schedule();
+            return;
+        }
+        log::info!("Removing download for video_id {}", id);
+        if let Some(video) = self.videos.remove(&id) {
+            for fetch in video.fetches.iter() {
+                fetch.stop.store(true, Ordering::Relaxed);
+            }
+            // Keep what was downloaded so that the track resumes from there.
+            if let Some(task) = video.task {
+                task.save_index();
+            }
+        }
+        self.reported_progress.remove(&id);
+        self.schedule();
+    }
+
+    fn request(&mut self, read: IncomingTask) {
+        log::debug!("Requested data {:#?}", read);
+        if !self.videos.contains_key(&read.video_id) {
+            self.videos.insert(read.video_id.clone(), Video::default());
+            self.start(&read);
+        }
+        let video = self.videos.get_mut(&read.video_id).expect("Just inserted");
+        video.playhead = read.pos;
+        // A new read tries again.
+        video.failed = false;
+        match video.task.clone() {
+            Some(task) => {
+                self.serve(&task, read);
+                self.schedule();
+            }
+            None => video.pending.push(read),
+        }
+    }
+
+    /// Starts the download of the video `read` is for.
+    fn start(&self, read: &IncomingTask) {
+        log::debug!("trying to create new download of {}", read.video_id);
+        let inbox = self.inbox_sender.clone();
+        let url = read.url.clone();
+        let video_id = read.video_id.clone();
+        let file_path = read.file_path.clone();
+        async_std::task::spawn(async move {
+            let mut task =
+                DownloadTask::start_new_task(url.clone(), video_id.clone(), file_path.clone())
+                    .await;
+            if matches!(&task, Err(err) if refresh::is_expired(err)) {
+                task = match refresh::renew(&video_id, &url).await {
+                    Ok(url) => DownloadTask::start_new_task(url, video_id.clone(), file_path).await,
+                    Err(err) => Err(DownloadError::Resolve(err.to_string()).into()),
+                };
+            }
+            let _ = inbox.unbounded_send(Message::Started(video_id, task));
+        });
+    }
+
+    /// Resolves the stream URL of `video_id` again, unless it is already being resolved.
+    fn refresh(&mut self, video_id: &str) {
+        let url = match self.videos.get_mut(video_id) {
+            Some(Video {
+                task: Some(task),
+                refreshing,
+                ..
+            }) if !*refreshing => {
+                *refreshing = true;
+                task.url()
+            }
+            _ => return,
+        };
+        log::info!("Stream url of {} expired, resolving it again", video_id);
+        let inbox = self.inbox_sender.clone();
+        let video_id = video_id.to_string();
+        async_std::task::spawn(async move {
+            let mut backoff = Backoff::new();
+            let url = loop {
+                match refresh::renew(&video_id, &url).await {
+                    Ok(url) => break Ok(url),
+                    Err(err) => match backoff.next_delay() {
+                        Some(delay) => {
+                            log::warn!("Cant resolve stream url, retrying {:#?}", err);
+                            async_std::task::sleep(delay).await;
+                        }
+                        None => break Err(DownloadError::Resolve(err.to_string())),
+                    },
+                }
+            };
+            let _ = inbox.unbounded_send(Message::Refreshed(video_id, url));
+        });
+    }
+
+    fn refreshed(&mut self, video_id: String, url: Result<String, DownloadError>) {
+        let video = match self.videos.get_mut(&video_id) {
+            Some(video) => video,
+            None => return,
+        };
+        video.refreshing = false;
+        match url {
+            Ok(url) => {
+                if let Some(task) = &video.task {
+                    task.set_url(url);
+                }
+            }
+            Err(err) => {
+                log::warn!("Cant resolve stream url of {} {:#?}", video_id, err);
+                self.fail_video(&video_id, err);
+            }
+        }
+        self.schedule();
+    }
+
+    /// Fails the reads waiting on `video_id` and stops fetching it until the next read, which
+    /// would only fail the same way.
+    fn fail_video(&mut self, video_id: &str, error: DownloadError) {
+        let pending = match self.videos.get_mut(video_id) {
+            Some(video) => {
+                video.failed = true;
+                std::mem::take(&mut video.pending)
+            }
+            None => return,
+        };
+        for read in pending {
+            self.fail(read, error.clone());
+        }
+        self.notify(DownloadEvent::Failed {
+            video_id: video_id.to_string(),
+            message: error.to_string(),
+        });
+    }
+
+    fn started(&mut self, video_id: String, task: Result<DownloadTask, anyhow::Error>) {
+        let video = match self.videos.get_mut(&video_id) {
+            Some(video) if video.task.is_none() => video,
+            // Removed while starting.
+            _ => return,
+        };
+        match task {
+            Ok(task) => {
+                task.bandwidth
+                    .set_rate(self.options.max_track_bytes_per_sec);
+                let task = Arc::new(task);
+                video.task = Some(task.clone());
+                for read in std::mem::take(&mut video.pending) {
+                    self.serve(&task, read);
+                }
+                self.schedule();
+            }
+            Err(err) => {
+                log::warn!("Cant create download task {:#?}", err);
+                let error = DownloadError::from_anyhow(&err);
+                for read in std::mem::take(&mut video.pending) {
+                    self.fail(read, error.clone());
+                }
+                self.videos.remove(&video_id);
+                self.notify(DownloadEvent::Failed {
+                    video_id,
+                    message: err.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Answers `read` if its bytes are there, otherwise makes it wait for a fetch.
+    fn serve(&mut self, task: &Arc<DownloadTask>, read: IncomingTask) {
+        let video = match self.videos.get_mut(&read.video_id) {
+            Some(video) => video,
+            None => return,
+        };
+        let data = task
+            .chunks
+            .read()
+            .expect("Cant lock chunks")
+            .read(read.pos, read.buff)
+            .to_vec();
+        if data.is_empty() && read.pos < video.limit() {
+            video.pending.push(read);
+            return;
+        }
+        log::debug!("returning cache len {}", data.len());
+        self.reply(read, data);
+    }
+
+    /// Answers the reads of `video_id` whose bytes arrived.
+    fn answer_pending(&mut self, video_id: &str) {
+        let (task, pending) = match self.videos.get_mut(video_id) {
+            Some(Video {
+                task: Some(task),
+                pending,
+                ..
+            }) => (task.clone(), std::mem::take(pending)),
+            _ => return,
+        };
+        let mut waiting = vec![];
+        for read in pending {
+            let data = task
+                .chunks
+                .read()
+                .expect("Cant lock chunks")
+                .read(read.pos, read.buff)
+                .to_vec();
+            if data.is_empty() {
+                waiting.push(read);
+            } else {
+                self.reply(read, data);
+            }
+        }
+        if let Some(video) = self.videos.get_mut(video_id) {
+            video.pending.extend(waiting);
+        }
+    }
+
+    /// Hands the free workers the chunks wanted most, and stops fetching chunks no longer
+    /// wanted, as after a seek.
+    fn schedule(&mut self) {
+        let options = self.options;
+        let size = options.chunk_size.max(FETCH_CHUNK);
+        let mut running = self
+            .videos
+            .values()
+            .flat_map(|video| video.fetches.iter())
+            .filter(|fetch| !fetch.stop.load(Ordering::Relaxed))
+            .count();
+        // Videos with reads waiting go first.
+        let mut ids = self.videos.keys().cloned().collect::<Vec<_>>();
+        ids.sort_by_key(|id| self.videos[id].pending.is_empty());
+
+        let mut spawn = vec![];
+        for id in ids {
+            let video = &self.videos[&id];
+            let task = match &video.task {
+                Some(task) if !video.refreshing && !video.failed => task.clone(),
+                _ => continue,
+            };
+            let wanted = video.wanted_chunks(&options);
+            for fetch in video.fetches.iter() {
+                if !wanted.contains(&fetch.chunk) && !fetch.stop.swap(true, Ordering::Relaxed) {
+                    log::debug!("Stopping fetch of chunk {} of {}", fetch.chunk, id);
+                    running -= 1;
+                }
+            }
+            let chunks = task.chunks.read().expect("Cant lock chunks");
+            for chunk in wanted {
+                if running >= options.workers.max(1) {
+                    break;
+                }
+                if video.fetching(chunk) {
+                    continue;
+                }
+                let chunk_end = ((chunk + 1) * size).min(video.limit());
+                let start = chunks.next_missing(chunk * size);
+                if start >= chunk_end {
+                    continue;
+                }
+                // Only fetch up to the bytes already there.
+                let end = chunks
+                    .next_stored(start)
+                    .unwrap_or(chunk_end)
+                    .min(chunk_end);
+                spawn.push((task.clone(), chunk, start, end));
+                running += 1;
+            }
+        }
+        for (task, chunk, start, end) in spawn {
+            self.spawn_fetch(&task, chunk, start, end);
+        }
+    }
+
+    fn spawn_fetch(&mut self, task: &Arc<DownloadTask>, chunk: usize, start: usize, end: usize) {
+        let fetch = Fetch {
+            id: self.next_fetch,
+            chunk,
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        self.next_fetch += 1;
+        log::debug!("Fetching {}..{} of {}", start, end, task.video_id);
+        async_std::task::spawn(run_fetch(
+            task.clone(),
+            start,
+            end,
+            fetch.id,
+            fetch.stop.clone(),
+            self.inbox_sender.clone(),
+            self.bandwidth.clone(),
+        ));
+        if let Some(video) = self.videos.get_mut(&task.video_id) {
+            video.fetches.push(fetch);
+        }
+    }
+
+    fn fetch_ended(
+        &mut self,
+        video_id: String,
+        fetch: u64,
+        range: std::ops::Range<usize>,
+        fetched: usize,
+        stopped: bool,
+        error: Option<DownloadError>,
+    ) {
+        let video = match self.videos.get_mut(&video_id) {
+            Some(video) => video,
+            None => return,
+        };
+        video.fetches.retain(|f| f.id != fetch);
+        let mut ended = vec![];
+        if video.refreshing {
+            // The fetch failed on the expired URL, its reads wait for the new one.
+        } else if let Some(error) = error {
+            // Retrying did not help.
+            self.fail_video(&video_id, error);
+        } else if fetched == 0 && !stopped {
+            // The server has nothing past the start, reads there are at the end of the file.
+            video.eof = Some(video.eof.unwrap_or(usize::MAX).min(range.start));
+            let (at_end, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut video.pending)
+                .into_iter()
+                .partition(|read| read.pos >= range.start);
+            video.pending = waiting;
+            ended = at_end;
+        }
+        for read in ended {
+            self.reply(read, vec![]);
+        }
+        self.answer_pending(&video_id);
+        self.schedule();
+        self.report_progress();
+    }
+
+    /// Brings the audio cache back within its limits, after a download completed. The files of
+    /// the videos being downloaded, the ones playing among them, are kept.
+    fn enforce_cache(&self) {
+        let keep = self.videos.keys().cloned().collect::<Vec<_>>();
+        async_std::task::spawn_blocking(move || {
+            if let Some(cache) = crate::cache::CacheManager::open() {
+                cache.enforce(&crate::config::Config::load().cache, &keep);
+            }
+        });
+    }
+
+    fn reply(&self, task: IncomingTask, data: Vec<u8>) {
+        self.send_reply(Reply {
+            task,
+            data: Ok(data),
+        });
+    }
+
+    fn fail(&self, task: IncomingTask, error: DownloadError) {
+        self.send_reply(Reply {
+            task,
+            data: Err(error),
+        });
+    }
+
+    fn send_reply(&self, reply: Reply) {
+        let responder = self
+            .subscribers
+            .get(&reply.task.stream)
+            .map(|(_, sender)| sender)
+            .unwrap_or(&self.responder);
+        if let Err(err) = responder.send(reply) {
+            log::warn!("Cant reply {:#?}", err);
+        }
+    }
+}
+
+impl DownloaderS {
+    fn notify(&mut self, event: DownloadEvent) {
+        self.watchers
+            .retain(|watcher| watcher.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Tells the watchers about every download that progressed by at least a percent, and about
+    /// the ones still downloading every `REPORT_INTERVAL`.
+    fn report_progress(&mut self) {
+        let mut events = vec![];
+        let now = Instant::now();
+        for (video_id, video) in self.videos.iter() {
+            let task = match &video.task {
+                Some(task) => task,
+                None => continue,
+            };
+            let percent = task.percent();
+            let due = match self.reported_progress.get(video_id) {
+                Some((reported, at)) => {
+                    *reported != percent.floor() as u32
+                        || (!video.fetches.is_empty() && now.duration_since(*at) >= REPORT_INTERVAL)
+                }
+                None => true,
+            };
+            if due {
+                self.reported_progress
+                    .insert(video_id.clone(), (percent.floor() as u32, now));
+                let buffered = task
+                    .chunks
+                    .read()
+                    .expect("Cant lock chunks")
+                    .available(video.playhead);
+                events.push(DownloadEvent::Progress {
+                    video_id: video_id.clone(),
+                    percent,
+                    bytes_per_sec: video.bytes_per_sec(),
+                    buffered_secs: buffered as f64 / video.byte_rate(),
+                });
+            }
+        }
+        for event in events {
+            self.notify(event);
+        }
+    }
+}
+
+/// Downloads the bytes of `task` from `start` to `end`, storing what arrives, until it runs into
+/// bytes already stored or `stop` is set. Failures are retried from where the fetch stopped, with
+/// a growing delay.
+async fn run_fetch(
+    task: Arc<DownloadTask>,
+    start: usize,
+    end: usize,
+    id: u64,
+    stop: Arc<AtomicBool>,
+    inbox: UnboundedSender<Message>,
+    bandwidth: Arc<TokenBucket>,
+) {
+    let mut fetched = 0;
+    let mut pos = start;
+    let mut expired = false;
+    let mut backoff = Backoff::new();
+    let error = loop {
+        let before = fetched;
+        let err = match fetch_range(
+            &task,
+            &mut pos,
+            end,
+            &stop,
+            &inbox,
+            &bandwidth,
+            &mut fetched,
+        )
+        .await
+        {
+            Ok(()) => break None,
+            Err(err) => err,
+        };
+        if refresh::is_expired(&err) {
+            expired = true;
+            break None;
+        }
+        let error = DownloadError::from_anyhow(&err);
+        if fetched > before {
+            backoff.reset();
+        }
+        match backoff.next_delay() {
+            Some(delay) if error.is_transient() && !stop.load(Ordering::Relaxed) => {
+                log::warn!("Download of {} failed, retrying {}", task.video_id, error);
+                async_std::task::sleep(delay).await;
+            }
+            _ => {
+                log::warn!("Download of {} failed {}", task.video_id, error);
+                break Some(error);
+            }
+        }
+    };
+    let _ = inbox.unbounded_send(Message::FetchEnded {
+        video_id: task.video_id.clone(),
+        fetch: id,
+        start,
+        end,
+        fetched,
+        stopped: stop.load(Ordering::Relaxed),
+        expired,
+        error,
+    });
+}
+
+/// One request of a fetch, from `pos` to `end`. `pos` and `fetched` follow what is stored so
+/// that a retry resumes there.
+async fn fetch_range(
+    task: &DownloadTask,
+    pos: &mut usize,
+    end: usize,
+    stop: &AtomicBool,
+    inbox: &UnboundedSender<Message>,
+    bandwidth: &TokenBucket,
+    fetched: &mut usize,
+) -> Result<(), anyhow::Error> {
+    let mut prog =
+        DownloadProg::new_download_at(&task.url(), *pos, Some(end), task.client.clone()).await?;
+    while !stop.load(Ordering::Relaxed) && *pos < end {
+        let data = prog.read(FETCH_CHUNK.min(end - *pos)).await?;
+        if data.is_empty() {
+            break;
+        }
+        let added = task.persist(*pos, &data).await;
+        *fetched += data.len();
+        let _ = inbox.unbounded_send(Message::Data(task.video_id.clone(), added));
+        if added < data.len() {
+            log::debug!("Fetch ran into downloaded bytes at {}", pos);
+            break;
+        }
+        *pos = prog.current_pos;
+        task.bandwidth.consume(data.len()).await;
+        bandwidth.consume(data.len()).await;
+    }
+    Ok(())
+}
+
+/// Notifications for whoever watches the downloads.
+#[derive(Clone, Debug)]
+pub enum DownloadEvent {
+    /// Share of a video downloaded so far, in percent, with how fast it downloads and how much
+    /// audio is downloaded ahead of the position read.
+    Progress {
+        video_id: String,
+        percent: f64,
+        bytes_per_sec: f64,
+        buffered_secs: f64,
+    },
+    /// A stream is waiting for data that is not downloaded yet.
+    Stalled(String),
+    /// A stalled stream received its data.
+    Resumed(String),
+    Failed {
+        video_id: String,
+        message: String,
+    },
+}
+
+#[derive(Clone)]
+pub enum DownloaderInput {
+    DownloadTask(IncomingTask),
+    /// The stream with this id was closed.
+    RemoveDownload(StreamId),
+    /// Sends the replies to the reads of a stream, by its id and the video it reads, to the given
+    /// channel.
+    Subscribe(StreamId, String, crossbeam_channel::Sender<Reply>),
+    /// Sends download events to the given channel.
+    Watch(futures::channel::mpsc::UnboundedSender<DownloadEvent>),
+    /// Bytes per second of audio in a video, to size its read-ahead window.
+    ByteRate(String, f64),
+    /// Replaces the options the downloader was created with.
+    SetOptions(DownloadOptions),
+}
+
+impl DownloaderInput {
+    /// The video the input is about, none for inputs about a stream or the whole downloader.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            DownloaderInput::DownloadTask(task) => Some(&task.video_id),
+            DownloaderInput::RemoveDownload(_) => None,
+            DownloaderInput::Subscribe(_, id, _) => Some(id),
+            DownloaderInput::Watch(_) => None,
+            DownloaderInput::ByteRate(id, _) => Some(id),
+            DownloaderInput::SetOptions(_) => None,
+        }
+    }
+
+    pub fn as_download_task(&self) -> Option<&IncomingTask> {
+        if let Self::DownloadTask(v) = self {
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_remove_download(&self) -> Option<&StreamId> {
+        if let Self::RemoveDownload(v) = self {
+            Some(v)
+        } else {
+            None
+        }
+    }
+}
+
+/// Id of a stream reading through the downloader. Several streams can read the same video.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct StreamId(pub u64);
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct IncomingTask {
+    /// Id of the stream reading.
+    pub stream: StreamId,
+    pub url: String,
+    pub pos: usize,
+    pub buff: usize,
+    pub video_id: String,
+    pub file_path: Option<String>,
+}
+
+pub struct Reply {
+    pub task: IncomingTask,
+    /// The bytes read, empty at the end of the file.
+    pub data: Result<Vec<u8>, DownloadError>,
+}
+
+/// Why the bytes of a read could not be downloaded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DownloadError {
+    /// The connection failed or broke off.
+    Network(String),
+    /// The server answered with an error status.
+    Status(u16),
+    /// The video could not be resolved to a stream URL.
+    Resolve(String),
+}
+
+impl DownloadError {
+    /// Whether trying again later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Network(_) => true,
+            DownloadError::Status(status) => *status == 408 || *status == 429 || *status >= 500,
+            DownloadError::Resolve(_) => false,
+        }
+    }
+
+    fn from_anyhow(err: &anyhow::Error) -> Self {
+        err.downcast_ref::<DownloadError>()
+            .cloned()
+            .unwrap_or_else(|| DownloadError::Network(err.to_string()))
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Network(message) => write!(f, "Network error {}", message),
+            DownloadError::Status(status) => write!(f, "Server answered with status {}", status),
+            DownloadError::Resolve(message) => write!(f, "Cant resolve stream {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// A video being downloaded, shared by the downloader and the fetches filling it in.
+pub struct DownloadTask {
+    /// Replaced when it expires.
+    url: RwLock<String>,
+    pub len: usize,
+    /// Bytes downloaded so far.
+    pub chunks: RwLock<ChunkStore>,
+    pub client: surf::Client,
+    pub file_name: Option<String>,
+    /// Where the bytes downloaded are written, when the download is cached.
+    cache_file: Option<CacheFile>,
+    pub video_id: String,
+    /// Whether the cache file holds the complete download.
+    pub has_cached: AtomicBool,
+    /// Bytes downloaded when the range index of the cache file was last saved.
+    pub index_saved: AtomicUsize,
+    /// Bandwidth cap of this download.
+    pub bandwidth: TokenBucket,
+}
+
+pub struct DownloadProg {
+    current_pos: usize,
+    response: surf::Response,
+    /// The range starts past the end of the file.
+    at_end: bool,
+}
+
+impl DownloadProg {
+    /// Starts downloading from `pos`, up to `end` or the end of the file.
+    pub async fn new_download_at(
+        url: &str,
+        pos: usize,
+        end: Option<usize>,
+        client: surf::Client,
+    ) -> Result<Self, anyhow::Error> {
+        log::info!("Creating new thread at position {} until {:?}", pos, end);
+        let range = match end {
+            Some(end) if end > pos => format!("bytes={}-{}", pos, end - 1),
+            _ => format!("bytes={}-", pos),
+        };
+        let response = client
+            .get(url)
+            .header("Range", range.as_str())
+            .send()
+            .await
+            .map_err(|e| DownloadError::Network(e.to_string()))?;
+        let status = response.status();
+        if refresh::is_expired_status(status) {
+            return Err(refresh::Expired.into());
+        }
+        let at_end = status == surf::StatusCode::RequestedRangeNotSatisfiable;
+        if !status.is_success() && !at_end {
+            return Err(DownloadError::Status(status as u16).into());
+        }
+        Ok(Self {
+            current_pos: pos,
+            response,
+            at_end,
+        })
+    }
+}
+
+impl DownloadProg {
+    /// Reads up to `size` bytes, none at the end of the range.
+    pub async fn read(&mut self, size: usize) -> Result<Vec<u8>, anyhow::Error> {
+        if self.at_end || self.response.is_empty() == Some(true) {
+            return Ok(vec![]);
+        }
+        let mut buff = vec![0; size];
+        let size = self
+            .response
+            .read(&mut buff[..])
+            .await
+            .map_err(|e| DownloadError::Network(e.to_string()))?;
+        self.current_pos += size;
+        buff.truncate(size);
+        Ok(buff)
+    }
+}
+
+impl DownloadTask {
+    async fn start_new_task(
+        url: String,
+        video_id: String,
+        file_name: Option<String>,
+    ) -> Result<Self, anyhow::Error> {
+        let client = crate::network::client();
+        if refresh::has_expired(&url) {
+            return Err(refresh::Expired.into());
+        }
+        let length = match surf::Url::parse(&url) {
+            Ok(url) => {
+                let mut backoff = Backoff::new();
+                let response = loop {
+                    let error = match client.get(&url).await {
+                        Ok(response) if refresh::is_expired_status(response.status()) => {
+                            return Err(refresh::Expired.into());
+                        }
+                        Ok(response) if response.status().is_success() => break response,
+                        Ok(response) => DownloadError::Status(response.status() as u16),
+                        Err(err) => DownloadError::Network(err.to_string()),
+                    };
+                    match backoff.next_delay() {
+                        Some(delay) if error.is_transient() => {
+                            log::warn!("Cant get content length, retrying {}", error);
+                            async_std::task::sleep(delay).await;
+                        }
+                        _ => return Err(error.into()),
+                    }
+                };
+                response.len().unwrap_or(0)
+            }
+            Err(err) => return Err(DownloadError::Resolve(err.to_string()).into()),
+        };
+        log::info!("Content length found {}", length);
+
+        let cached = match &file_name {
+            Some(path) => cache::load(path, length).await,
+            None => None,
+        };
+        let (chunks, has_cached) = cached.unwrap_or_else(|| (ChunkStore::new(length), false));
+        let index_saved = chunks.downloaded();
+        let task = Self {
+            url: RwLock::new(url),
+            len: length,
+            bandwidth: TokenBucket::new(None),
+
+            has_cached: AtomicBool::new(has_cached),
+            index_saved: AtomicUsize::new(index_saved),
+            chunks: RwLock::new(chunks),
+            client,
+            video_id,
+            cache_file: file_name.clone().map(CacheFile::new),
+            file_name,
+        };
+        // The index goes first, a cache file without one would pass for a complete download.
+        task.save_index();
+        Ok(task)
+    }
+
+    pub fn url(&self) -> String {
+        self.url.read().expect("Cant lock url").clone()
+    }
+
+    fn set_url(&self, url: String) {
+        *self.url.write().expect("Cant lock url") = url;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.chunks.read().expect("Cant lock chunks").is_complete()
+    }
+
+    /// Share of the file downloaded so far, in percent.
+    fn percent(&self) -> f64 {
+        let chunks = self.chunks.read().expect("Cant lock chunks");
+        if chunks.len() == 0 {
+            0.0
+        } else {
+            chunks.downloaded().min(chunks.len()) as f64 * 100.0 / chunks.len() as f64
+        }
+    }
+
+    /// Stores downloaded `data` at `pos`, returning how many of its bytes were new.
+    fn store(&self, pos: usize, data: &[u8]) -> usize {
+        self.chunks
+            .write()
+            .expect("Cant lock chunks")
+            .insert(pos, data)
+    }
+
+    /// Saves the ranges downloaded so far next to a partial cache file.
+    fn save_index(&self) {
+        if self.has_cached.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(path) = &self.file_name {
+            let (ranges, downloaded) = {
+                let chunks = self.chunks.read().expect("Cant lock chunks");
+                (chunks.ranges(), chunks.downloaded())
+            };
+            RangeIndex {
+                content_length: self.len,
+                ranges,
+            }
+            .save(path);
+            self.index_saved.store(downloaded, Ordering::SeqCst);
+        }
+    }
+
+    /// Stores downloaded `data` at `pos` and writes it to the cache file, which is completed once
+    /// every byte is there. Returns how many of its bytes were new.
+    async fn persist(&self, pos: usize, data: &[u8]) -> usize {
+        if let (Some(file), false) = (&self.cache_file, self.has_cached.load(Ordering::SeqCst)) {
+            // Written before it is stored, so that the index never lists bytes not on disk.
+            if let Err(err) = file.write_at(pos, data).await {
+                log::warn!("Cant write to cache file {:#?}", err);
+            }
+        }
+        let added = self.store(pos, data);
+        let downloaded = self.chunks.read().expect("Cant lock chunks").downloaded();
+        if downloaded >= self.index_saved.load(Ordering::SeqCst) + INDEX_SAVE_BYTES {
+            self.save_index();
+        }
+        if self.is_complete() {
+            self.cache_to_file().await;
+        }
+        added
+    }
+
+    /// Marks the cache file complete. Every byte was written to it as it arrived, so only the
+    /// range index is left to remove.
+    async fn cache_to_file(&self) {
+        // Only the first fetch to complete the file finishes it.
+        if self.has_cached.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        log::info!("Download complete");
+        if let (Some(path), Some(file)) = (&self.file_name, &self.cache_file) {
+            file.close().await;
+            RangeIndex::remove(path);
+        }
+    }
+}
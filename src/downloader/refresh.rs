@@ -0,0 +1,114 @@
+//! Stream URLs expire after a few hours, googlevideo then answers range requests with 403 or 410.
+//! An expired URL is resolved again through the extractor, for the same video and format, and the
+//! download resumes where it was.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusty_pipe::youtube_extractor::stream_extractor::YTStreamExtractor;
+
+use crate::yt_downloader::YTDownloader;
+
+/// The server refused the URL of a stream because it expired.
+#[derive(Debug)]
+pub struct Expired;
+
+impl std::fmt::Display for Expired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stream url expired")
+    }
+}
+
+impl std::error::Error for Expired {}
+
+/// Whether the server answered with a status meaning the URL expired.
+pub fn is_expired_status(status: surf::StatusCode) -> bool {
+    matches!(status, surf::StatusCode::Forbidden | surf::StatusCode::Gone)
+}
+
+pub fn is_expired(err: &anyhow::Error) -> bool {
+    err.is::<Expired>()
+}
+
+fn query_param(url: &str, name: &str) -> Option<String> {
+    let url = surf::Url::parse(url).ok()?;
+    let value = url
+        .query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.to_string());
+    value
+}
+
+/// Format of the stream, from the `itag` parameter of its URL.
+pub fn itag(url: &str) -> Option<i32> {
+    query_param(url, "itag")?.parse().ok()
+}
+
+/// Whether the `expire` parameter of the URL, in seconds since the epoch, is past.
+pub fn has_expired(url: &str) -> bool {
+    let expire = match query_param(url, "expire").and_then(|e| e.parse::<u64>().ok()) {
+        Some(expire) => expire,
+        None => return false,
+    };
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() >= expire)
+        .unwrap_or(false)
+}
+
+/// Resolves `expired_url` again, in the same format. Fails if the URL does not tell its format.
+pub async fn renew(video_id: &str, expired_url: &str) -> Result<String, anyhow::Error> {
+    let itag = itag(expired_url).ok_or_else(|| anyhow::anyhow!("No itag in stream url"))?;
+    resolve(video_id, Some(itag), false).await
+}
+
+/// Resolves the audio stream of `video_id` to its URL and length, for playing it. The stream with
+/// the lowest bitrate is picked if `lowest_bitrate` is set.
+pub async fn resolve_stream(
+    video_id: &str,
+    lowest_bitrate: bool,
+) -> Result<(String, Option<usize>), anyhow::Error> {
+    let url = resolve(video_id, None, lowest_bitrate).await?;
+    let response = crate::network::client()
+        .get(&url)
+        .send()
+        .await
+        .map_err(|err| anyhow::anyhow!("Cant get stream of {} {}", video_id, err))?;
+    let length = response.len();
+    Ok((url, length))
+}
+
+/// Resolves the URL of the audio stream of `video_id` in format `itag`. Fails if that format is
+/// not offered anymore, the bytes of another one cant resume a partial download. Without `itag`,
+/// picks the m4a stream with the lowest bitrate if `lowest_bitrate` is set, the first one
+/// otherwise.
+pub async fn resolve(
+    video_id: &str,
+    itag: Option<i32>,
+    lowest_bitrate: bool,
+) -> Result<String, anyhow::Error> {
+    log::info!("Resolving stream url of {} itag {:?}", video_id, itag);
+    let extractor = YTStreamExtractor::new(video_id, YTDownloader {})
+        .await
+        .map_err(|e| anyhow::anyhow!("{:#?}", e))?;
+    let audio_streams = extractor
+        .get_audio_streams()
+        .await
+        .map_err(|_| anyhow::anyhow!("Cant get audio streams"))?;
+    let mut m4a_streams = audio_streams.iter().filter(|f| f.mimeType.contains("mp4"));
+    let stream_info = match itag {
+        Some(itag) => audio_streams
+            .iter()
+            .find(|f| f.itag == itag)
+            .ok_or_else(|| anyhow::anyhow!("Stream with itag {} not offered anymore", itag))?,
+        None if lowest_bitrate => m4a_streams
+            .min_by_key(|f| f.bitrate)
+            .ok_or_else(|| anyhow::anyhow!("No m4a stream found"))?,
+        None => m4a_streams
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No m4a stream found"))?,
+    };
+    stream_info
+        .url
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("No url in stream"))
+}
@@ -0,0 +1,81 @@
+//! Retries of failed requests, waiting twice as long after every failure with some randomness so
+//! that the fetches of a download dont all retry at the same moment.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Wait before the first retry.
+const BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(16);
+/// Retries after which a failure is given up on.
+const MAX_RETRIES: u32 = 5;
+
+#[derive(Default)]
+pub struct Backoff {
+    retries: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait before the next attempt, None once the retries are used up. The wait is
+    /// picked between half and all of the exponential delay.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.retries >= MAX_RETRIES {
+            return None;
+        }
+        let delay = BASE_DELAY
+            .checked_mul(1 << self.retries)
+            .unwrap_or(MAX_DELAY)
+            .min(MAX_DELAY);
+        self.retries += 1;
+        Some(delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0)))
+    }
+
+    /// Starts over after an attempt that made progress.
+    pub fn reset(&mut self) {
+        self.retries = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_with_jitter() {
+        let mut backoff = Backoff::new();
+        let mut expected = BASE_DELAY;
+        for _ in 0..MAX_RETRIES {
+            let delay = backoff.next_delay().expect("Retries left");
+            assert!(delay >= expected / 2 && delay <= expected, "{:?}", delay);
+            assert!(delay <= MAX_DELAY);
+            expected = (expected * 2).min(MAX_DELAY);
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        let mut backoff = Backoff::new();
+        for _ in 0..MAX_RETRIES {
+            assert!(backoff.next_delay().is_some());
+        }
+        assert!(backoff.next_delay().is_none());
+        assert!(backoff.next_delay().is_none());
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new();
+        for _ in 0..MAX_RETRIES {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        let delay = backoff.next_delay().expect("Retries left after reset");
+        assert!(delay <= BASE_DELAY);
+    }
+}
@@ -0,0 +1,120 @@
+//! Bandwidth caps, as token buckets refilled at the allowed rate. A fetch takes tokens for the
+//! bytes it received and waits while the bucket is in debt.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Bytes per second, None for no cap.
+    rate: Option<u64>,
+    /// Bytes that can be received right away, negative while in debt.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().expect("Cant lock bucket");
+        state.rate = rate;
+        state.tokens = 0.0;
+        state.last_refill = Instant::now();
+    }
+
+    /// Takes `bytes` tokens and returns how long to wait before receiving more.
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().expect("Cant lock bucket");
+        let rate = match state.rate {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => return Duration::from_secs(0),
+        };
+        let now = Instant::now();
+        let refill = now.duration_since(state.last_refill).as_secs_f64() * rate;
+        // Bursts are bounded to a second worth of bytes.
+        state.tokens = (state.tokens + refill).min(rate) - bytes as f64;
+        state.last_refill = now;
+        if state.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+
+    /// Accounts for `bytes` received, waiting as long as the cap requires.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = self.take(bytes);
+        if wait > Duration::from_secs(0) {
+            async_std::task::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pretends the bucket was last refilled `secs` ago.
+    fn age(bucket: &TokenBucket, secs: u64) {
+        let mut state = bucket.state.lock().unwrap();
+        state.last_refill = Instant::now() - Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn uncapped_never_waits() {
+        let bucket = TokenBucket::new(None);
+        assert_eq!(bucket.take(10_000_000), Duration::from_secs(0));
+        let bucket = TokenBucket::new(Some(0));
+        assert_eq!(bucket.take(10_000_000), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn debt_is_waited_off_at_the_rate() {
+        let bucket = TokenBucket::new(Some(1000));
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(490) && wait <= Duration::from_millis(500));
+        // The debt adds up.
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(990) && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bursts_are_bounded_to_a_second() {
+        let bucket = TokenBucket::new(Some(1000));
+        age(&bucket, 10);
+        assert_eq!(bucket.take(1000), Duration::from_secs(0));
+        let wait = bucket.take(1000);
+        assert!(wait > Duration::from_millis(990) && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn set_rate_clears_the_debt() {
+        let bucket = TokenBucket::new(Some(1000));
+        bucket.take(5000);
+        bucket.set_rate(None);
+        assert_eq!(bucket.take(5000), Duration::from_secs(0));
+        bucket.set_rate(Some(1000));
+        let wait = bucket.take(100);
+        assert!(wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn consume_without_cap_returns_at_once() {
+        let bucket = TokenBucket::new(None);
+        let start = Instant::now();
+        async_std::task::block_on(bucket.consume(1_000_000));
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,96 @@
+//! Second order IIR filter sections shared by the processing stages.
+
+use std::f64::consts::PI;
+
+/// Transposed direct form II biquad with normalized coefficients (`a[0]` is 1).
+#[derive(Clone, Copy, Default)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self { b, a, z: [0.0; 2] }
+    }
+
+    /// Peaking filter boosting or cutting `gain_db` around `f0`, from the RBJ audio EQ cookbook.
+    pub fn peaking(rate: u32, f0: f64, q: f64, gain_db: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * f0 / rate as f64;
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha / a;
+        Self::new(
+            [
+                (1.0 + alpha * a) / a0,
+                -2.0 * w0.cos() / a0,
+                (1.0 - alpha * a) / a0,
+            ],
+            [1.0, -2.0 * w0.cos() / a0, (1.0 - alpha / a) / a0],
+        )
+    }
+
+    /// Takes the coefficients of `other`, keeping the filter state so the change is smooth.
+    pub fn set_coefficients(&mut self, other: &Biquad) {
+        self.b = other.b;
+        self.a = other.a;
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gain of `filter` for a sine at `frequency`, in dB, measured over the second half of a
+    /// second so that the filter settled.
+    fn gain_db(mut filter: Biquad, rate: u32, frequency: f64) -> f64 {
+        let frames = rate as usize;
+        let (mut input, mut output) = (0.0, 0.0);
+        for frame in 0..frames {
+            let x = (2.0 * PI * frequency * frame as f64 / rate as f64).sin();
+            let y = filter.process(x);
+            if frame >= frames / 2 {
+                input += x * x;
+                output += y * y;
+            }
+        }
+        10.0 * (output / input).log10()
+    }
+
+    #[test]
+    fn peaking_gain_at_the_center_frequency() {
+        for gain in [-12.0, -6.0, 3.0, 6.0, 12.0].iter() {
+            let filter = Biquad::peaking(48000, 1000.0, 1.41, *gain);
+            let measured = gain_db(filter, 48000, 1000.0);
+            assert!(
+                (measured - gain).abs() < 0.05,
+                "{} dB for {}",
+                measured,
+                gain
+            );
+        }
+    }
+
+    #[test]
+    fn peaking_leaves_distant_frequencies() {
+        let filter = Biquad::peaking(44100, 1000.0, 1.41, 12.0);
+        assert!(gain_db(filter, 44100, 31.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn flat_peaking_passes_through() {
+        let mut filter = Biquad::peaking(48000, 1000.0, 1.41, 0.0);
+        for frame in 0..1000 {
+            let x = (frame as f64 * 0.1).sin();
+            assert!((filter.process(x) - x).abs() < 1e-9);
+        }
+    }
+}
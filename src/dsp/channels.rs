@@ -0,0 +1,233 @@
+//! Channel mixing: the user's mono and balance options, and the mapping of the track's channels
+//! onto the channels of the output device.
+
+use symphonia::core::audio::{AudioBuffer, Channels, Signal};
+
+use super::AudioProcessor;
+
+/// Gain of a centre or surround channel folded into a front channel, -3 dB.
+const FOLD_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelOptions {
+    /// Plays the average of all channels on every channel.
+    pub mono: bool,
+    /// From -1 for left only to 1 for right only, 0 being centered. Applies to the first two
+    /// channels, tracks with a single channel are not affected.
+    pub balance: f64,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            mono: false,
+            balance: 0.0,
+        }
+    }
+}
+
+/// Applies `ChannelOptions` to planar audio.
+#[derive(Default)]
+pub struct ChannelMixer {
+    options: ChannelOptions,
+}
+
+impl ChannelMixer {
+    pub fn set_options(&mut self, options: ChannelOptions) {
+        self.options = ChannelOptions {
+            mono: options.mono,
+            balance: options.balance.max(-1.0).min(1.0),
+        };
+    }
+}
+
+impl AudioProcessor for ChannelMixer {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        let channels = buf.spec().channels.count();
+        if channels < 2 {
+            return;
+        }
+        if self.options.mono {
+            for frame in 0..buf.frames() {
+                let sum: f32 = (0..channels).map(|ch| buf.chan(ch)[frame]).sum();
+                let avg = sum / channels as f32;
+                for ch in 0..channels {
+                    buf.chan_mut(ch)[frame] = avg;
+                }
+            }
+        }
+        let balance = self.options.balance as f32;
+        if balance != 0.0 {
+            // Turn the far side down, the near side stays at unity.
+            let (left, right) = ((1.0 - balance).min(1.0), (1.0 + balance).min(1.0));
+            buf.chan_mut(0).iter_mut().for_each(|s| *s *= left);
+            buf.chan_mut(1).iter_mut().for_each(|s| *s *= right);
+        }
+    }
+}
+
+/// Mixes interleaved frames of the track's channels into the channel count of a device.
+///
+/// Channels the device has are passed through. Channels it lacks are folded into the front left
+/// and right, and a device with a single channel gets their average. Mono tracks play on both
+/// front channels.
+pub struct ChannelMap {
+    from: usize,
+    to: usize,
+    /// Gain of every input channel into every output channel, output major.
+    matrix: Vec<f32>,
+}
+
+impl ChannelMap {
+    pub fn new(channels: Channels, to: usize) -> Self {
+        let from = channels.count();
+        let to = to.max(1);
+        let mut matrix = vec![0.0; from * to];
+        if to == 1 {
+            for gain in matrix.iter_mut() {
+                *gain = 1.0 / from.max(1) as f32;
+            }
+        } else if from == 1 {
+            matrix[0] = 1.0;
+            matrix[from] = 1.0;
+        } else {
+            for (i, channel) in channels.iter().enumerate() {
+                if i < to {
+                    matrix[i * from + i] = 1.0;
+                } else {
+                    let (left, right) = fold_gains(channel);
+                    matrix[i] += left;
+                    matrix[from + i] += right;
+                }
+            }
+            // Keep the folded front channels from clipping.
+            for out in 0..2 {
+                let row = &mut matrix[out * from..(out + 1) * from];
+                let total: f32 = row.iter().sum();
+                if total > 1.0 {
+                    row.iter_mut().for_each(|g| *g /= total);
+                }
+            }
+        }
+        Self { from, to, matrix }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.from == self.to
+    }
+
+    pub fn apply(&self, samples: &[f32]) -> Vec<f32> {
+        if self.is_identity() {
+            return samples.to_vec();
+        }
+        let mut out = Vec::with_capacity(samples.len() / self.from.max(1) * self.to);
+        for frame in samples.chunks_exact(self.from) {
+            for row in self.matrix.chunks_exact(self.from) {
+                out.push(row.iter().zip(frame).map(|(g, s)| g * s).sum());
+            }
+        }
+        out
+    }
+}
+
+/// Gains of `channel` into the front left and right when the device does not have it.
+fn fold_gains(channel: Channels) -> (f32, f32) {
+    if channel == Channels::LFE1 {
+        (0.0, 0.0)
+    } else if channel == Channels::FRONT_CENTRE || channel == Channels::REAR_CENTRE {
+        (FOLD_GAIN, FOLD_GAIN)
+    } else if channel.intersects(
+        Channels::FRONT_LEFT
+            | Channels::REAR_LEFT
+            | Channels::SIDE_LEFT
+            | Channels::FRONT_LEFT_CENTRE
+            | Channels::FRONT_LEFT_WIDE
+            | Channels::FRONT_LEFT_HIGH
+            | Channels::REAR_LEFT_CENTRE
+            | Channels::TOP_FRONT_LEFT
+            | Channels::TOP_REAR_LEFT,
+    ) {
+        (FOLD_GAIN, 0.0)
+    } else if channel.intersects(
+        Channels::FRONT_RIGHT
+            | Channels::REAR_RIGHT
+            | Channels::SIDE_RIGHT
+            | Channels::FRONT_RIGHT_CENTRE
+            | Channels::FRONT_RIGHT_WIDE
+            | Channels::FRONT_RIGHT_HIGH
+            | Channels::REAR_RIGHT_CENTRE
+            | Channels::TOP_FRONT_RIGHT
+            | Channels::TOP_REAR_RIGHT,
+    ) {
+        (0.0, FOLD_GAIN)
+    } else {
+        (0.5, 0.5)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo() -> Channels {
+        Channels::FRONT_LEFT | Channels::FRONT_RIGHT
+    }
+
+    fn surround_5_1() -> Channels {
+        stereo()
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn mono_plays_on_both_front_channels() {
+        let map = ChannelMap::new(Channels::FRONT_LEFT, 2);
+        assert!(!map.is_identity());
+        assert_close(&map.apply(&[0.5, -0.25]), &[0.5, 0.5, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn same_channel_count_passes_through() {
+        let map = ChannelMap::new(stereo(), 2);
+        assert!(map.is_identity());
+        assert_close(&map.apply(&[0.1, 0.2, 0.3, 0.4]), &[0.1, 0.2, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn surround_downmix_coefficients() {
+        let map = ChannelMap::new(surround_5_1(), 2);
+        // Fronts at unity, centre and rears at -3 dB, the LFE dropped, then scaled so that
+        // each side sums to one.
+        let total = 1.0 + 2.0 * FOLD_GAIN;
+        let (front, fold) = (1.0 / total, FOLD_GAIN / total);
+        let expected = [
+            [front, 0.0],
+            [0.0, front],
+            [fold, fold],
+            [0.0, 0.0],
+            [fold, 0.0],
+            [0.0, fold],
+        ];
+        for (ch, gains) in expected.iter().enumerate() {
+            let mut frame = [0.0; 6];
+            frame[ch] = 1.0;
+            assert_close(&map.apply(&frame), gains);
+        }
+        assert_close(&map.apply(&[1.0; 6]), &[1.0, 1.0]);
+    }
+
+    #[test]
+    fn stereo_to_a_single_channel_is_averaged() {
+        let map = ChannelMap::new(stereo(), 1);
+        assert_close(&map.apply(&[1.0, 0.0, 0.5, 0.5]), &[0.5, 0.5]);
+    }
+}
@@ -0,0 +1,146 @@
+//! Samples decoded ahead of playback and the crossfade that mixes them into another track.
+
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+
+use symphonia::core::audio::{AudioBuffer, Signal, SignalSpec};
+
+/// Planar `f32` samples waiting to be played, one queue per channel.
+pub struct SampleQueue {
+    spec: SignalSpec,
+    channels: Vec<VecDeque<f32>>,
+}
+
+impl SampleQueue {
+    pub fn new(spec: SignalSpec) -> Self {
+        Self {
+            spec,
+            channels: vec![VecDeque::new(); spec.channels.count()],
+        }
+    }
+
+    pub fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    /// Number of frames queued.
+    pub fn frames(&self) -> usize {
+        self.channels.first().map(|c| c.len()).unwrap_or(0)
+    }
+
+    pub fn push(&mut self, buf: &AudioBuffer<f32>) {
+        for (ch, queue) in self.channels.iter_mut().enumerate() {
+            queue.extend(buf.chan(ch).iter());
+        }
+    }
+
+    /// Moves as many queued frames as fit into `buf`, replacing its contents. Returns false if
+    /// nothing was queued.
+    pub fn pop_into(&mut self, buf: &mut AudioBuffer<f32>) -> bool {
+        let n = self.frames().min(buf.capacity());
+        if n == 0 {
+            return false;
+        }
+        buf.clear();
+        buf.render_reserved(Some(n));
+        for (ch, queue) in self.channels.iter_mut().enumerate() {
+            for (out, sample) in buf.chan_mut(ch).iter_mut().zip(queue.drain(..n)) {
+                *out = sample;
+            }
+        }
+        true
+    }
+
+    /// Fades `buf` out and the queued samples in with equal-power gains, consuming one queued
+    /// frame per frame of `buf`. `progress` is how far the fade is at the first frame, from 0 to 1,
+    /// and advances by `step` every frame.
+    pub fn mix_into(&mut self, buf: &mut AudioBuffer<f32>, progress: f32, step: f32) {
+        let n = buf.frames().min(self.frames());
+        for (ch, queue) in self.channels.iter_mut().enumerate() {
+            let out = buf.chan_mut(ch);
+            for (i, sample) in queue.drain(..n).enumerate() {
+                let angle = (progress + step * i as f32).max(0.0).min(1.0) * FRAC_PI_2;
+                out[i] = out[i] * angle.cos() + sample * angle.sin();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::Channels;
+
+    const RATE: u32 = 48000;
+
+    fn constant(
+        channels: Channels,
+        frames: usize,
+        value: impl Fn(usize) -> f32,
+    ) -> AudioBuffer<f32> {
+        super::super::test_buffer(RATE, channels, frames, |ch, _| value(ch))
+    }
+
+    /// Crossfades `frames` frames of 1.0 into 1.0 in calls of `chunk` frames, returning the mix.
+    fn fade(frames: usize, chunk: usize) -> Vec<f32> {
+        let mono = Channels::FRONT_LEFT;
+        let mut queue = SampleQueue::new(SignalSpec::new(RATE, mono));
+        queue.push(&constant(mono, frames, |_| 1.0));
+        let step = 1.0 / frames as f32;
+        let mut mixed = vec![];
+        for start in (0..frames).step_by(chunk) {
+            let mut buf = constant(mono, chunk.min(frames - start), |_| 1.0);
+            queue.mix_into(&mut buf, start as f32 * step, step);
+            mixed.extend_from_slice(buf.chan(0));
+        }
+        mixed
+    }
+
+    #[test]
+    fn crossfade_is_continuous_across_calls() {
+        let whole = fade(1000, 1000);
+        let chunked = fade(1000, 64);
+        assert_eq!(whole.len(), chunked.len());
+        for (a, b) in whole.iter().zip(chunked.iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        // The mix moves by no more than the gains do from one frame to the next.
+        let max_jump = std::f32::consts::SQRT_2 * FRAC_PI_2 / 1000.0 + 1e-5;
+        for pair in chunked.windows(2) {
+            assert!((pair[1] - pair[0]).abs() <= max_jump);
+        }
+        assert!((chunked[0] - 1.0).abs() < 1e-6);
+        assert!((chunked[999] - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn crossfade_keeps_the_power() {
+        // The outgoing track on the left channel and the incoming one on the right.
+        let stereo = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+        let mut queue = SampleQueue::new(SignalSpec::new(RATE, stereo));
+        queue.push(&constant(stereo, 500, |ch| ch as f32));
+        let mut buf = constant(stereo, 500, |ch| 1.0 - ch as f32);
+        queue.mix_into(&mut buf, 0.0, 1.0 / 500.0);
+        for (out, into) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+            assert!((out * out + into * into - 1.0).abs() < 1e-5);
+        }
+        assert_eq!(queue.frames(), 0);
+    }
+
+    #[test]
+    fn queue_pops_what_was_pushed() {
+        let mono = Channels::FRONT_LEFT;
+        let mut queue = SampleQueue::new(SignalSpec::new(RATE, mono));
+        queue.push(&super::super::test_buffer(RATE, mono, 300, |_, i| i as f32));
+        let mut buf = AudioBuffer::new(200, SignalSpec::new(RATE, mono));
+        assert!(queue.pop_into(&mut buf));
+        assert_eq!(
+            buf.chan(0),
+            &(0..200).map(|i| i as f32).collect::<Vec<_>>()[..]
+        );
+        assert!(queue.pop_into(&mut buf));
+        assert_eq!(buf.frames(), 100);
+        assert_eq!(buf.chan(0)[0], 200.0);
+        assert!(!queue.pop_into(&mut buf));
+    }
+}
@@ -0,0 +1,260 @@
+//! Ten band graphic equalizer.
+//!
+//! Every band is a peaking filter at a fixed ISO octave frequency. When bands are boosted the
+//! whole signal is attenuated by the largest boost first, so the equalizer never adds clipping.
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+use super::{biquad::Biquad, AudioProcessor};
+
+/// Center frequencies of the bands, in Hz.
+pub const BAND_FREQUENCIES: [f64; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+/// Largest boost or cut of a band, in dB.
+pub const MAX_BAND_GAIN_DB: f64 = 12.0;
+/// Quality factor giving octave wide bands.
+const BAND_Q: f64 = 1.41;
+
+/// Named band gains, in dB from the lowest band to the highest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EqualizerPreset {
+    pub name: String,
+    pub gains: Vec<f64>,
+}
+
+impl EqualizerPreset {
+    fn new(name: &str, gains: [f64; 10]) -> Self {
+        Self {
+            name: name.to_string(),
+            gains: gains.to_vec(),
+        }
+    }
+}
+
+/// Presets available without any configuration.
+pub fn builtin_presets() -> Vec<EqualizerPreset> {
+    vec![
+        EqualizerPreset::new("flat", [0.0; 10]),
+        EqualizerPreset::new(
+            "bass_boost",
+            [6.0, 5.0, 4.0, 2.5, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ),
+        EqualizerPreset::new(
+            "treble_boost",
+            [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.5, 3.0, 4.5, 6.0],
+        ),
+        EqualizerPreset::new(
+            "vocal",
+            [-2.0, -2.0, -1.0, 0.5, 2.5, 3.5, 3.0, 1.5, 0.0, -1.0],
+        ),
+        EqualizerPreset::new(
+            "spoken_word",
+            [-6.0, -4.0, -2.0, 0.0, 2.0, 3.5, 3.5, 2.0, 0.0, -2.0],
+        ),
+        EqualizerPreset::new(
+            "rock",
+            [4.5, 3.5, 2.0, -0.5, -1.5, -0.5, 1.5, 3.0, 3.5, 4.0],
+        ),
+        EqualizerPreset::new(
+            "electronic",
+            [4.5, 4.0, 1.5, 0.0, -2.0, 1.5, 0.5, 1.5, 4.0, 5.0],
+        ),
+        EqualizerPreset::new(
+            "classical",
+            [4.0, 3.0, 2.5, 2.0, -1.0, -1.0, 0.0, 2.0, 3.0, 3.5],
+        ),
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EqualizerOptions {
+    pub enabled: bool,
+    /// Gain of every band in dB, one per entry of `BAND_FREQUENCIES`.
+    pub gains: Vec<f64>,
+    /// Preset the gains were taken from, if they were not edited since.
+    pub preset: Option<String>,
+}
+
+impl Default for EqualizerOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            gains: vec![0.0; BAND_FREQUENCIES.len()],
+            preset: Some("flat".to_string()),
+        }
+    }
+}
+
+impl EqualizerOptions {
+    /// Gains of `preset`, clamped to the supported range and padded or cut to the band count.
+    pub fn from_preset(enabled: bool, preset: &EqualizerPreset) -> Self {
+        let mut options = Self {
+            enabled,
+            gains: preset.gains.clone(),
+            preset: Some(preset.name.clone()),
+        };
+        options.sanitize();
+        options
+    }
+
+    pub fn set_band(&mut self, band: usize, gain_db: f64) {
+        if let Some(gain) = self.gains.get_mut(band) {
+            *gain = gain_db;
+            self.preset = None;
+        }
+        self.sanitize();
+    }
+
+    pub fn sanitize(&mut self) {
+        self.gains.resize(BAND_FREQUENCIES.len(), 0.0);
+        for gain in self.gains.iter_mut() {
+            *gain = gain.max(-MAX_BAND_GAIN_DB).min(MAX_BAND_GAIN_DB);
+        }
+    }
+
+    fn is_flat(&self) -> bool {
+        self.gains.iter().all(|g| *g == 0.0)
+    }
+}
+
+pub struct Equalizer {
+    options: EqualizerOptions,
+    rate: u32,
+    /// One filter per band for every channel.
+    filters: Vec<Vec<Biquad>>,
+    /// Linear gain applied ahead of the bands to make room for boosts.
+    preamp: f64,
+}
+
+impl Default for Equalizer {
+    fn default() -> Self {
+        Self {
+            options: EqualizerOptions::default(),
+            rate: 0,
+            filters: vec![],
+            preamp: 1.0,
+        }
+    }
+}
+
+impl Equalizer {
+    pub fn set_options(&mut self, options: &EqualizerOptions) {
+        if &self.options != options {
+            self.options = options.clone();
+            self.update_coefficients();
+        }
+    }
+
+    fn band_filters(&self) -> Vec<Biquad> {
+        BAND_FREQUENCIES
+            .iter()
+            .zip(self.options.gains.iter())
+            .map(|(f0, gain)| {
+                // Keep the top bands below Nyquist at low sample rates.
+                let f0 = f0.min(self.rate as f64 * 0.45);
+                Biquad::peaking(self.rate, f0, BAND_Q, *gain)
+            })
+            .collect()
+    }
+
+    fn update_coefficients(&mut self) {
+        let max_boost = self.options.gains.iter().cloned().fold(0.0, f64::max);
+        self.preamp = 10f64.powf(-max_boost / 20.0);
+        if self.rate == 0 {
+            return;
+        }
+        let bands = self.band_filters();
+        for filters in self.filters.iter_mut() {
+            for (filter, band) in filters.iter_mut().zip(bands.iter()) {
+                filter.set_coefficients(band);
+            }
+        }
+    }
+}
+
+impl AudioProcessor for Equalizer {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        if !self.options.enabled || self.options.is_flat() {
+            return;
+        }
+        let spec = *buf.spec();
+        let channels = spec.channels.count();
+        if self.rate != spec.rate || self.filters.len() != channels {
+            self.rate = spec.rate;
+            self.filters = vec![self.band_filters(); channels];
+            self.update_coefficients();
+        }
+        for (ch, filters) in self.filters.iter_mut().enumerate() {
+            for sample in buf.chan_mut(ch) {
+                let mut x = *sample as f64 * self.preamp;
+                for filter in filters.iter_mut() {
+                    x = filter.process(x);
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::Channels;
+
+    /// Gain of `equalizer` for a sine at `frequency`, in dB, measured over the second half of a
+    /// second so that the filters settled.
+    fn gain_db(equalizer: &mut Equalizer, frequency: f64) -> f64 {
+        let rate = 48000;
+        let mut buf =
+            super::super::test_buffer(rate, Channels::FRONT_LEFT, rate as usize, |_, i| {
+                super::super::sine(rate, frequency, 0.5, i)
+            });
+        let input = buf.chan(0).to_vec();
+        equalizer.process(&mut buf);
+        let half = rate as usize / 2;
+        let energy = |samples: &[f32]| {
+            samples[half..]
+                .iter()
+                .map(|s| (*s as f64).powi(2))
+                .sum::<f64>()
+        };
+        10.0 * (energy(buf.chan(0)) / energy(&input)).log10()
+    }
+
+    #[test]
+    fn disabled_equalizer_passes_through() {
+        let mut options = EqualizerOptions::default();
+        options.set_band(5, 12.0);
+        let mut equalizer = Equalizer::default();
+        equalizer.set_options(&options);
+        assert!(gain_db(&mut equalizer, 1000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn boosted_band_is_made_room_for() {
+        let mut options = EqualizerOptions {
+            enabled: true,
+            ..EqualizerOptions::default()
+        };
+        options.set_band(5, 12.0);
+        let mut equalizer = Equalizer::default();
+        equalizer.set_options(&options);
+        // The boost at 1 kHz is taken back by the preamp, everything else is cut by it.
+        assert!(gain_db(&mut equalizer, 1000.0).abs() < 0.2);
+        let mut equalizer = Equalizer::default();
+        equalizer.set_options(&options);
+        assert!((gain_db(&mut equalizer, 62.0) + 12.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn band_gains_are_clamped() {
+        let mut options = EqualizerOptions::default();
+        options.set_band(0, 40.0);
+        options.set_band(1, -40.0);
+        assert_eq!(options.gains[0], MAX_BAND_GAIN_DB);
+        assert_eq!(options.gains[1], -MAX_BAND_GAIN_DB);
+        assert_eq!(options.preset, None);
+    }
+}
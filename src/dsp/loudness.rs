@@ -0,0 +1,473 @@
+//! Loudness normalization following ITU-R BS.1770 / EBU R128.
+//!
+//! The meter K-weights every channel, measures gated 400 ms blocks and derives the integrated
+//! loudness of what it has seen so far. The normalizer turns that into a gain towards a target
+//! loudness, and the limiter, last in the chain, keeps the result under a true-peak ceiling.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+use super::{biquad::Biquad, AudioProcessor};
+
+/// Blocks quieter than this never count towards the integrated loudness.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// Blocks this far below the ungated loudness do not count either.
+const RELATIVE_GATE_LU: f64 = -10.0;
+/// How much audio the running estimate needs before it is trusted, in 100 ms sub-blocks.
+const MIN_ESTIMATE_SUB_BLOCKS: usize = 30;
+/// Bounds of the normalization gain, in dB.
+const MIN_GAIN_DB: f64 = -24.0;
+const MAX_GAIN_DB: f64 = 12.0;
+/// Time constant of the normalization gain smoothing, in seconds.
+const GAIN_SMOOTHING_SECS: f32 = 0.5;
+/// Release time of the limiter, in seconds.
+const LIMITER_RELEASE_SECS: f32 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormalizationOptions {
+    pub enabled: bool,
+    /// Integrated loudness tracks are brought to, in LUFS.
+    pub target_lufs: f64,
+    /// Ceiling of the limiter, in dBTP.
+    pub true_peak_db: f64,
+}
+
+impl Default for NormalizationOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target_lufs: -14.0,
+            true_peak_db: -1.0,
+        }
+    }
+}
+
+/// Measurement persisted next to a cached track.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LoudnessInfo {
+    pub integrated_lufs: f64,
+}
+
+impl LoudnessInfo {
+    /// Path of the measurement file kept next to the cached track at `file_path`.
+    pub fn sidecar_path(file_path: &str) -> PathBuf {
+        Path::new(file_path).with_extension("loudness")
+    }
+
+    pub fn load(file_path: &str) -> Option<Self> {
+        let data = std::fs::read(Self::sidecar_path(file_path)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn save(&self, file_path: &str) {
+        match serde_json::to_vec(self) {
+            Ok(data) => {
+                if let Err(err) = std::fs::write(Self::sidecar_path(file_path), data) {
+                    log::warn!("Cant save loudness {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant serialize loudness {:#?}", err),
+        }
+    }
+}
+
+/// The two stage K-weighting pre-filter of BS.1770 for `rate`.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate as f64;
+
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn lufs_to_energy(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// Integrated loudness meter.
+pub struct LoudnessMeter {
+    rate: u32,
+    filters: Vec<[Biquad; 2]>,
+    sub_block_frames: usize,
+    sub_block_pos: usize,
+    sub_block_sum: f64,
+    /// Mean square of the last four 100 ms sub-blocks, forming one 400 ms gating block.
+    recent_sub_blocks: Vec<f64>,
+    sub_blocks_seen: usize,
+    /// Mean square of every gating block measured so far.
+    blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        Self {
+            rate,
+            filters: vec![k_weighting(rate); channels],
+            sub_block_frames: (rate as usize / 10).max(1),
+            sub_block_pos: 0,
+            sub_block_sum: 0.0,
+            recent_sub_blocks: Vec::with_capacity(4),
+            sub_blocks_seen: 0,
+            blocks: vec![],
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Feeds `buf` to the meter. Returns true if a new gating block was completed.
+    pub fn push(&mut self, buf: &AudioBuffer<f32>) -> bool {
+        let channels = buf.spec().channels.count().min(self.filters.len());
+        let mut new_block = false;
+        for frame in 0..buf.frames() {
+            for ch in 0..channels {
+                let [shelf, high_pass] = &mut self.filters[ch];
+                let y = high_pass.process(shelf.process(buf.chan(ch)[frame] as f64));
+                self.sub_block_sum += y * y;
+            }
+            self.sub_block_pos += 1;
+            if self.sub_block_pos == self.sub_block_frames {
+                if self.recent_sub_blocks.len() == 4 {
+                    self.recent_sub_blocks.remove(0);
+                }
+                self.recent_sub_blocks
+                    .push(self.sub_block_sum / self.sub_block_frames as f64);
+                self.sub_blocks_seen += 1;
+                self.sub_block_pos = 0;
+                self.sub_block_sum = 0.0;
+                if self.recent_sub_blocks.len() == 4 {
+                    self.blocks
+                        .push(self.recent_sub_blocks.iter().sum::<f64>() / 4.0);
+                    new_block = true;
+                }
+            }
+        }
+        new_block
+    }
+
+    /// Whether enough audio was measured for `integrated` to be a usable estimate.
+    pub fn has_estimate(&self) -> bool {
+        self.sub_blocks_seen >= MIN_ESTIMATE_SUB_BLOCKS
+    }
+
+    /// Gated integrated loudness of everything measured so far, in LUFS.
+    pub fn integrated(&self) -> Option<f64> {
+        let absolute_gate = lufs_to_energy(ABSOLUTE_GATE_LUFS);
+        let above_absolute = self
+            .blocks
+            .iter()
+            .filter(|e| **e > absolute_gate)
+            .collect::<Vec<_>>();
+        if above_absolute.is_empty() {
+            return None;
+        }
+        let ungated = above_absolute.iter().copied().sum::<f64>() / above_absolute.len() as f64;
+        let relative_gate = lufs_to_energy(energy_to_lufs(ungated) + RELATIVE_GATE_LU);
+        let gated = above_absolute
+            .into_iter()
+            .filter(|e| **e > relative_gate)
+            .collect::<Vec<_>>();
+        if gated.is_empty() {
+            return None;
+        }
+        Some(energy_to_lufs(
+            gated.iter().copied().sum::<f64>() / gated.len() as f64,
+        ))
+    }
+}
+
+/// Brings a track to the target loudness, using a stored measurement when there is one and the
+/// running estimate of the meter otherwise.
+pub struct Normalizer {
+    options: NormalizationOptions,
+    meter: Option<LoudnessMeter>,
+    measured: Option<f64>,
+    estimate: Option<f64>,
+    /// Linear gain applied to the last frame.
+    gain: f32,
+}
+
+impl Normalizer {
+    pub fn new(measured: Option<LoudnessInfo>) -> Self {
+        Self {
+            options: NormalizationOptions::default(),
+            meter: None,
+            measured: measured.map(|m| m.integrated_lufs),
+            estimate: None,
+            gain: 1.0,
+        }
+    }
+
+    pub fn set_options(&mut self, options: NormalizationOptions) {
+        self.options = options;
+    }
+
+    /// Whether the loudness came from a stored measurement rather than from this playback.
+    pub fn has_stored_measurement(&self) -> bool {
+        self.measured.is_some()
+    }
+
+    /// Integrated loudness measured during this playback.
+    pub fn measurement(&self) -> Option<LoudnessInfo> {
+        self.meter
+            .as_ref()
+            .and_then(|meter| meter.integrated())
+            .map(|integrated_lufs| LoudnessInfo { integrated_lufs })
+    }
+
+    fn target_gain(&self) -> f32 {
+        match self.measured.or(self.estimate) {
+            Some(loudness) => {
+                let db = (self.options.target_lufs - loudness)
+                    .max(MIN_GAIN_DB)
+                    .min(MAX_GAIN_DB);
+                10f64.powf(db / 20.0) as f32
+            }
+            None => 1.0,
+        }
+    }
+}
+
+/// Catmull-Rom estimate of the largest magnitude between `p1` and `p2`, approximating the
+/// inter-sample peaks a true-peak meter would see.
+fn inter_sample_peak(p0: f32, p1: f32, p2: f32, p3: f32) -> f32 {
+    let mut peak = p1.abs().max(p2.abs());
+    for t in [0.25f32, 0.5, 0.75].iter() {
+        let t = *t;
+        let t2 = t * t;
+        let t3 = t2 * t;
+        let v = 0.5
+            * ((2.0 * p1)
+                + (-p0 + p2) * t
+                + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+                + (-p0 + 3.0 * p1 - 3.0 * p2 + p3) * t3);
+        peak = peak.max(v.abs());
+    }
+    peak
+}
+
+impl AudioProcessor for Normalizer {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        let spec = *buf.spec();
+        let channels = spec.channels.count();
+        let reset = match &self.meter {
+            Some(meter) => meter.rate() != spec.rate || meter.channels() != channels,
+            None => true,
+        };
+        if reset {
+            self.meter = Some(LoudnessMeter::new(spec.rate, channels));
+        }
+
+        if let Some(meter) = &mut self.meter {
+            if meter.push(buf) && meter.has_estimate() {
+                self.estimate = meter.integrated();
+            }
+        }
+
+        if !self.options.enabled {
+            self.gain = 1.0;
+            return;
+        }
+
+        let target = self.target_gain();
+        let smoothing = 1.0 / (GAIN_SMOOTHING_SECS * spec.rate as f32);
+        for frame in 0..buf.frames() {
+            self.gain += (target - self.gain) * smoothing;
+            for ch in 0..channels {
+                buf.chan_mut(ch)[frame] *= self.gain;
+            }
+        }
+    }
+}
+
+/// Keeps the samples under the true-peak ceiling of the normalization options. It runs after
+/// every stage that adds gain, so the equalizer and the channel mix are limited too.
+pub struct Limiter {
+    /// Linear ceiling, none while normalization is off.
+    ceiling: Option<f32>,
+    /// Linear gain applied to the last frame.
+    gain: f32,
+    /// Last two samples of every channel, used to estimate inter-sample peaks.
+    history: Vec<[f32; 2]>,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self {
+            ceiling: None,
+            gain: 1.0,
+            history: vec![],
+        }
+    }
+}
+
+impl Limiter {
+    /// Limits to the ceiling of `options` once the output scaled the samples by `volume`, so a
+    /// volume above 100% lowers the ceiling the samples are kept under.
+    pub fn set_options(&mut self, options: NormalizationOptions, volume: f32) {
+        self.ceiling = if options.enabled {
+            Some(10f64.powf(options.true_peak_db / 20.0) as f32 / volume.max(1.0))
+        } else {
+            None
+        };
+    }
+}
+
+impl AudioProcessor for Limiter {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        let spec = *buf.spec();
+        let channels = spec.channels.count();
+        if self.history.len() != channels {
+            self.history = vec![[0.0; 2]; channels];
+        }
+        let ceiling = match self.ceiling {
+            Some(ceiling) => ceiling,
+            None => {
+                self.gain = 1.0;
+                return;
+            }
+        };
+
+        let release = 1.0 / (LIMITER_RELEASE_SECS * spec.rate as f32);
+        for frame in 0..buf.frames() {
+            let mut peak = 0f32;
+            for ch in 0..channels {
+                let x = buf.chan(ch)[frame];
+                let [p0, p1] = self.history[ch];
+                peak = peak.max(inter_sample_peak(p0, p1, x, x));
+                self.history[ch] = [p1, x];
+            }
+
+            self.gain += (1.0 - self.gain) * release;
+            if peak * self.gain > ceiling {
+                self.gain = ceiling / peak;
+            }
+
+            for ch in 0..channels {
+                buf.chan_mut(ch)[frame] *= self.gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::Channels;
+
+    /// Integrated loudness of `secs` seconds of a stereo 1 kHz sine with a peak of `dbfs`.
+    fn measure_sine(rate: u32, dbfs: f64, secs: usize) -> Option<f64> {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        let stereo = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+        let mut meter = LoudnessMeter::new(rate, 2);
+        let chunk = rate as usize / 10;
+        for start in (0..rate as usize * secs).step_by(chunk) {
+            let buf = super::super::test_buffer(rate, stereo, chunk, |_, i| {
+                super::super::sine(rate, 1000.0, amplitude, start + i)
+            });
+            meter.push(&buf);
+        }
+        meter.integrated()
+    }
+
+    #[test]
+    fn ebu_reference_tone_measures_minus_23_lufs() {
+        for rate in [44100, 48000].iter() {
+            let lufs = measure_sine(*rate, -23.0, 20).unwrap();
+            assert!((lufs + 23.0).abs() < 0.1, "{} LUFS at {}", lufs, rate);
+        }
+    }
+
+    #[test]
+    fn loudness_follows_the_level() {
+        let lufs = measure_sine(48000, -33.0, 10).unwrap();
+        assert!((lufs + 33.0).abs() < 0.1, "{} LUFS", lufs);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        assert_eq!(measure_sine(48000, -200.0, 5), None);
+    }
+
+    /// Largest magnitude of a second of a stereo sine with a peak of `amplitude`, limited for
+    /// `volume`.
+    fn limited_peak(amplitude: f64, volume: f32) -> f32 {
+        let rate = 48000;
+        let stereo = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+        let options = NormalizationOptions {
+            enabled: true,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::default();
+        limiter.set_options(options, volume);
+        let mut peak = 0f32;
+        for start in (0..rate as usize).step_by(4800) {
+            let mut buf = super::super::test_buffer(rate, stereo, 4800, |_, i| {
+                super::super::sine(rate, 1000.0, amplitude, start + i)
+            });
+            limiter.process(&mut buf);
+            for ch in 0..2 {
+                peak = buf.chan(ch).iter().fold(peak, |peak, s| peak.max(s.abs()));
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn limiter_keeps_peaks_under_the_ceiling() {
+        let ceiling = 10f32.powf(-1.0 / 20.0);
+        assert!(limited_peak(1.5, 1.0) <= ceiling + 1e-4);
+        // The volume applied after the limiter is accounted for.
+        assert!(limited_peak(1.0, 1.5) * 1.5 <= ceiling + 1e-4);
+        // Quiet audio goes through untouched.
+        assert!((limited_peak(0.5, 1.0) - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn estimate_needs_three_seconds() {
+        let mut meter = LoudnessMeter::new(48000, 1);
+        let buf = super::super::test_buffer(48000, Channels::FRONT_LEFT, 4800, |_, i| {
+            super::super::sine(48000, 1000.0, 0.5, i)
+        });
+        for _ in 0..29 {
+            meter.push(&buf);
+        }
+        assert!(!meter.has_estimate());
+        meter.push(&buf);
+        assert!(meter.has_estimate());
+    }
+}
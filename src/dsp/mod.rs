@@ -0,0 +1,56 @@
+//! Sample processing stages applied to decoded audio before it reaches the audio output.
+
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+mod biquad;
+pub mod channels;
+pub mod crossfade;
+pub mod equalizer;
+pub mod loudness;
+pub mod resample;
+pub mod stretch;
+
+/// A processing stage working in place on planar `f32` audio.
+pub trait AudioProcessor {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>);
+}
+
+/// Drops the first `frames` frames of `buf`, moving the rest to the front.
+pub fn trim_start(buf: &mut AudioBuffer<f32>, frames: usize) {
+    let frames = frames.min(buf.frames());
+    if frames == 0 {
+        return;
+    }
+    let remaining = buf.frames() - frames;
+    for ch in 0..buf.spec().channels.count() {
+        buf.chan_mut(ch).copy_within(frames.., 0);
+    }
+    // Rendering reserved frames keeps the samples already in place.
+    buf.clear();
+    buf.render_reserved(Some(remaining));
+}
+
+/// Buffer of `frames` frames at `rate` whose samples are `sample(channel, frame)`.
+#[cfg(test)]
+fn test_buffer(
+    rate: u32,
+    channels: symphonia::core::audio::Channels,
+    frames: usize,
+    sample: impl Fn(usize, usize) -> f32,
+) -> AudioBuffer<f32> {
+    let spec = symphonia::core::audio::SignalSpec::new(rate, channels);
+    let mut buf = AudioBuffer::new(frames as u64, spec);
+    buf.render_reserved(Some(frames));
+    for ch in 0..channels.count() {
+        for (frame, out) in buf.chan_mut(ch).iter_mut().enumerate() {
+            *out = sample(ch, frame);
+        }
+    }
+    buf
+}
+
+/// Sample `frame` of a sine at `frequency` with a peak of `amplitude`.
+#[cfg(test)]
+fn sine(rate: u32, frequency: f64, amplitude: f64, frame: usize) -> f32 {
+    (amplitude * (2.0 * std::f64::consts::PI * frequency * frame as f64 / rate as f64).sin()) as f32
+}
@@ -0,0 +1,148 @@
+//! Sample rate conversion for outputs running at another rate than the decoded audio.
+//!
+//! The converter keeps its filter state from one write to the next, so the output is continuous
+//! across packet boundaries. It lags the input by the length of its filter, the samples held
+//! back are flushed at the end of the stream and when the converter is replaced.
+
+use serde::{Deserialize, Serialize};
+
+/// Quality of the sinc filter used, trading accuracy for processing time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResampleQuality {
+    Fastest,
+    Medium,
+    Best,
+}
+
+impl Default for ResampleQuality {
+    fn default() -> Self {
+        ResampleQuality::Medium
+    }
+}
+
+impl ResampleQuality {
+    fn converter_type(self) -> samplerate::ConverterType {
+        match self {
+            ResampleQuality::Fastest => samplerate::ConverterType::SincFastest,
+            ResampleQuality::Medium => samplerate::ConverterType::SincMediumQuality,
+            ResampleQuality::Best => samplerate::ConverterType::SincBestQuality,
+        }
+    }
+}
+
+/// Streaming converter of interleaved samples from an input rate to a fixed output rate.
+pub struct Resampler {
+    quality: ResampleQuality,
+    from: u32,
+    to: u32,
+    channels: usize,
+    /// None while the rates match and samples pass through untouched.
+    converter: Option<samplerate::Samplerate>,
+    /// Samples flushed from a replaced converter, returned ahead of the next ones.
+    tail: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(quality: ResampleQuality, from: u32, to: u32, channels: usize) -> Self {
+        let mut resampler = Self {
+            quality,
+            from,
+            to,
+            channels,
+            converter: None,
+            tail: vec![],
+        };
+        resampler.rebuild();
+        resampler
+    }
+
+    /// Sets the rate of the samples passed in from now on, as when a track with another rate
+    /// follows.
+    pub fn set_input_rate(&mut self, from: u32) {
+        if self.from != from {
+            self.flush_converter();
+            self.from = from;
+            self.rebuild();
+        }
+    }
+
+    pub fn set_quality(&mut self, quality: ResampleQuality) {
+        if self.quality != quality {
+            self.flush_converter();
+            self.quality = quality;
+            self.rebuild();
+        }
+    }
+
+    /// Drops the filter state, for when the next samples do not follow the previous ones.
+    pub fn reset(&mut self) {
+        self.tail.clear();
+        if let Some(converter) = &self.converter {
+            if let Err(err) = converter.reset() {
+                log::warn!("Cant reset resampler {:#?}", err);
+            }
+        }
+    }
+
+    pub fn process(&mut self, samples: Vec<f32>) -> Vec<f32> {
+        let converted = match &self.converter {
+            Some(converter) => match converter.process(&samples) {
+                Ok(converted) => converted,
+                Err(err) => {
+                    log::warn!("Cant resample {:#?}", err);
+                    vec![]
+                }
+            },
+            None => samples,
+        };
+        if self.tail.is_empty() {
+            return converted;
+        }
+        let mut samples = std::mem::take(&mut self.tail);
+        samples.extend(converted);
+        samples
+    }
+
+    /// Returns the samples the filter still holds, at the end of the stream. The samples passed
+    /// in next start a new stream.
+    pub fn flush(&mut self) -> Vec<f32> {
+        self.flush_converter();
+        std::mem::take(&mut self.tail)
+    }
+
+    /// Moves what the converter holds to the tail and readies it for a new stream.
+    fn flush_converter(&mut self) {
+        if let Some(converter) = &self.converter {
+            match converter.process_last(&[]) {
+                Ok(converted) => self.tail.extend(converted),
+                Err(err) => log::warn!("Cant flush resampler {:#?}", err),
+            }
+            if let Err(err) = converter.reset() {
+                log::warn!("Cant reset resampler {:#?}", err);
+            }
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.converter = None;
+        if self.from == self.to {
+            return;
+        }
+        log::debug!(
+            "Resampling {} -> {} with {:?} quality",
+            self.from,
+            self.to,
+            self.quality
+        );
+        match samplerate::Samplerate::new(
+            self.quality.converter_type(),
+            self.from,
+            self.to,
+            self.channels,
+        ) {
+            Ok(converter) => self.converter = Some(converter),
+            Err(err) => log::error!("Cant create resampler {:#?}", err),
+        }
+    }
+}
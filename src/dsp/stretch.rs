@@ -0,0 +1,141 @@
+//! Pitch-preserving time stretching.
+//!
+//! The stretcher uses WSOLA: the input is cut into overlapping sequences which are spliced back
+//! together at a different hop. Each splice point is moved within a small seek window to where
+//! the waveform best matches the previous sequence, so no pitch shift or phasing is heard.
+
+/// Slowest playback rate accepted.
+pub const MIN_SPEED: f32 = 0.5;
+/// Fastest playback rate accepted.
+pub const MAX_SPEED: f32 = 3.0;
+
+/// Length of a spliced sequence, in seconds.
+const SEQUENCE_SECS: f32 = 0.04;
+/// Length of the crossfade between sequences, in seconds.
+const OVERLAP_SECS: f32 = 0.008;
+/// How far a splice point may move to find the best match, in seconds.
+const SEEK_SECS: f32 = 0.015;
+
+/// Streaming time stretcher working on interleaved samples.
+pub struct TimeStretcher {
+    channels: usize,
+    speed: f32,
+    sequence: usize,
+    overlap: usize,
+    seek: usize,
+    /// Interleaved input not consumed yet.
+    input: Vec<f32>,
+    /// End of the previous sequence, crossfaded into the start of the next one.
+    mid: Vec<f32>,
+    /// Fractional part of the input hop carried between sequences, in frames.
+    skip_fract: f64,
+}
+
+impl TimeStretcher {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        let frames = |secs: f32| ((secs * rate as f32) as usize).max(1);
+        Self {
+            channels: channels.max(1),
+            speed: 1.0,
+            sequence: frames(SEQUENCE_SECS),
+            overlap: frames(OVERLAP_SECS),
+            seek: frames(SEEK_SECS),
+            input: vec![],
+            mid: vec![],
+            skip_fract: 0.0,
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(MIN_SPEED).min(MAX_SPEED);
+    }
+
+    /// Drops everything held back.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.mid.clear();
+        self.skip_fract = 0.0;
+    }
+
+    /// Stretches `samples` by the current speed. Output lags the input by up to one sequence;
+    /// at unity speed anything held back is released and samples pass through untouched.
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        if (self.speed - 1.0).abs() < f32::EPSILON {
+            return self.flush(samples);
+        }
+        self.input.extend_from_slice(samples);
+
+        let ch = self.channels;
+        let mut output = vec![];
+        loop {
+            let hop = (self.sequence - self.overlap) as f64 * self.speed as f64 + self.skip_fract;
+            let skip = hop as usize;
+            if self.input.len() / ch < (self.seek + self.sequence).max(skip) {
+                break;
+            }
+            self.skip_fract = hop - skip as f64;
+
+            let offset = if self.mid.is_empty() {
+                // Nothing to splice onto yet, start with the sequence as it is.
+                output.extend_from_slice(&self.input[..self.overlap * ch]);
+                0
+            } else {
+                let offset = self.best_offset();
+                for i in 0..self.overlap {
+                    let fade = i as f32 / self.overlap as f32;
+                    for c in 0..ch {
+                        let s = (offset + i) * ch + c;
+                        output.push(self.mid[i * ch + c] * (1.0 - fade) + self.input[s] * fade);
+                    }
+                }
+                offset
+            };
+            let tail = (offset + self.sequence - self.overlap) * ch;
+            output.extend_from_slice(&self.input[(offset + self.overlap) * ch..tail]);
+            self.mid.clear();
+            self.mid
+                .extend_from_slice(&self.input[tail..(offset + self.sequence) * ch]);
+            self.input.drain(..skip * ch);
+        }
+        output
+    }
+
+    /// Releases everything held back, crossfading the last splice into `samples`.
+    fn flush(&mut self, samples: &[f32]) -> Vec<f32> {
+        let mut output = std::mem::take(&mut self.input);
+        output.extend_from_slice(samples);
+        let ch = self.channels;
+        let n = (self.mid.len() / ch).min(output.len() / ch);
+        for i in 0..n {
+            let fade = i as f32 / n as f32;
+            for c in 0..ch {
+                let s = i * ch + c;
+                output[s] = self.mid[s] * (1.0 - fade) + output[s] * fade;
+            }
+        }
+        self.mid.clear();
+        self.skip_fract = 0.0;
+        output
+    }
+
+    /// Offset into the seek window where the input best continues the previous sequence, by
+    /// normalized cross-correlation.
+    fn best_offset(&self) -> usize {
+        let ch = self.channels;
+        let len = self.overlap * ch;
+        let mut best = (0, f32::MIN);
+        for offset in 0..self.seek {
+            let window = &self.input[offset * ch..offset * ch + len];
+            let (mut corr, mut norm) = (0.0, 0.0);
+            for (a, b) in self.mid.iter().zip(window) {
+                corr += a * b;
+                norm += b * b;
+            }
+            let score = corr / norm.sqrt().max(f32::EPSILON);
+            if score > best.1 {
+                best = (offset, score);
+            }
+        }
+        best.0
+    }
+}
@@ -1,27 +1,36 @@
 use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use downloader::{DownloaderInput, IncomingTask, Reply};
+use downloader::{DownloadEvent, DownloaderInput, IncomingTask, Reply, StreamId};
 use symphonia::core::io::MediaSource;
 
 use crate::yt_downloader::YTDownloader;
 
+pub mod cache;
 pub mod cli_ui;
+mod config;
 pub mod decode_m4a;
 pub mod downloader;
+mod dsp;
+mod network;
 mod output;
 mod player;
 pub mod r_player;
 mod server;
 pub mod yt_downloader;
 
-pub fn run_server(port: u16) {
+pub use network::init as init_network;
+pub use output::Sink;
+pub use server::schema::{PlayOptions, PlayerMessage, PlayerState, ToPlayerMessages};
+
+pub fn run_server(port: u16, sink: Option<Sink>) {
     async_std::task::block_on(async {
         let (tx1, rx1) = futures::channel::mpsc::channel(2);
         let (tx2, rx2) = futures::channel::mpsc::channel(2);
 
         let server_fut = server::run_server(rx1, tx2, YTDownloader {  },port);
         // let cli_fut = crate::cli_ui::run_tui_pipe(rx1, tx2);
-        let player_fut = crate::r_player::run_audio_player(rx2, tx1);
+        let player_fut = crate::r_player::run_audio_player(rx2, tx1, sink);
         futures::join!(server_fut, player_fut);
     });
 }
@@ -32,6 +41,8 @@ pub fn get_unused_port() -> Option<u16> {
 }
 
 pub struct StreamResponse {
+    /// Tells the replies to this stream apart from those to other streams of the same video.
+    stream: StreamId,
     url: String,
     video_id: String,
     file_name: Option<String>,
@@ -39,11 +50,64 @@ pub struct StreamResponse {
     down_sender: crossbeam_channel::Sender<DownloaderInput>,
     down_rcv: crossbeam_channel::Receiver<Reply>,
     total_length: Option<usize>,
+    events: Option<futures::channel::mpsc::UnboundedSender<DownloadEvent>>,
+}
+
+/// Id of the next stream opened.
+static NEXT_STREAM: AtomicU64 = AtomicU64::new(0);
+
+/// How long a read waits for the downloader before the stream reports itself stalled.
+const STALL_AFTER: std::time::Duration = std::time::Duration::from_millis(250);
+
+impl StreamResponse {
+    /// Creates a stream read through the downloader. Replies to its reads are delivered to this
+    /// stream only, so several streams, of the same video too, can be read at the same time.
+    pub fn new(
+        url: String,
+        video_id: String,
+        file_name: Option<String>,
+        total_length: Option<usize>,
+        down_sender: crossbeam_channel::Sender<DownloaderInput>,
+    ) -> Self {
+        let (reply_sender, down_rcv) = crossbeam_channel::unbounded();
+        let stream = StreamId(NEXT_STREAM.fetch_add(1, Ordering::Relaxed));
+        let subscribe = DownloaderInput::Subscribe(stream, video_id.clone(), reply_sender);
+        if let Err(err) = down_sender.send(subscribe) {
+            log::error!("Cant subscribe to downloader {:#?}", err);
+        }
+        Self {
+            stream,
+            url,
+            video_id,
+            file_name,
+            current_position: 0,
+            down_sender,
+            down_rcv,
+            total_length,
+            events: None,
+        }
+    }
+
+    /// Reports reads waiting on the network to `events`.
+    pub fn with_events(
+        mut self,
+        events: futures::channel::mpsc::UnboundedSender<DownloadEvent>,
+    ) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    fn notify(&self, event: DownloadEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.unbounded_send(event);
+        }
+    }
 }
 
 impl Read for StreamResponse {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let task = IncomingTask {
+            stream: self.stream,
             url: self.url.to_string(),
             pos: self.current_position,
             buff: buf.len(),
@@ -53,24 +117,46 @@ impl Read for StreamResponse {
         log::debug!("trying to send downnload task to downloader");
         self.down_sender
             .send(DownloaderInput::DownloadTask(task.clone()))
-            .expect("Cant send to downloader");
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Downloader stopped"))?;
         // log::info!("Download with downloader size {}", buf.len());
+        let mut stalled = false;
         let mut data = loop {
             log::debug!("trying to recceive data from downloader");
-            let reply = self.down_rcv.recv().expect("Cant receive from downloader");
+            let reply = match self.down_rcv.recv_timeout(STALL_AFTER) {
+                Ok(reply) => reply,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    if !stalled {
+                        stalled = true;
+                        self.notify(DownloadEvent::Stalled(self.video_id.clone()));
+                    }
+                    continue;
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "Downloader stopped",
+                    ));
+                }
+            };
             if reply.task == task {
                 break reply;
             }
         };
-        // log::info!("downloaded data len {}", data.data.len());
-        self.current_position += data.data.len();
-        if data.data.len() == 0 {
+        if stalled {
+            self.notify(DownloadEvent::Resumed(self.video_id.clone()));
+        }
+        let data = data
+            .data
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        // log::info!("downloaded data len {}", data.len());
+        self.current_position += data.len();
+        if data.len() == 0 {
             return Ok(0);
         }
-        buf[..data.data.len()].copy_from_slice(&data.data[..]);
+        buf[..data.len()].copy_from_slice(&data[..]);
         // async_std::task::block_on(async { self.response.read(buf).await })
         log::debug!("Downloaded some bytes, sending to player to play");
-        Ok(data.data.len())
+        Ok(data.len())
     }
 }
 
@@ -78,7 +164,7 @@ impl Drop for StreamResponse {
     fn drop(&mut self) {
         if let Err(err) = self
             .down_sender
-            .send(DownloaderInput::RemoveDownload(self.video_id.clone()))
+            .send(DownloaderInput::RemoveDownload(self.stream))
         {
             log::error!("Cant remove download {:#?}", err);
         }
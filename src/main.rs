@@ -30,16 +30,36 @@ use rusty_pipe_cli::downloader::DownloaderS;
 use rusty_pipe_cli::downloader::IncomingTask;
 use rusty_pipe_cli::downloader::Reply;
 use rusty_pipe_cli::yt_downloader::YTDownloader;
+use rusty_pipe_cli::Sink;
 
 fn main() -> Result<(), Error> {
     pretty_env_logger::init();
 
-    let mut args = std::env::args();
-    if args.any(|arg| arg.contains("server")) {
+    let args = std::env::args().collect::<Vec<_>>();
+    // `--sink <sink>` sends the audio somewhere other than the audio device.
+    let sink = match args.iter().position(|arg| arg == "--sink") {
+        Some(i) => {
+            let sink = args.get(i + 1).map(|s| s.as_str()).unwrap_or_default();
+            Some(sink.parse::<Sink>().map_err(failure::err_msg)?)
+        }
+        None => None,
+    };
+    if args.get(1).map(|arg| arg.as_str()) == Some("cache") {
+        // `cache <command>` manages the audio cache and exits.
+        return rusty_pipe_cli::cache::run_command(&args[2..]).map_err(failure::err_msg);
+    }
+    rusty_pipe_cli::init_network().map_err(failure::err_msg)?;
+    if args.iter().any(|arg| arg.contains("server")) {
         let port = rusty_pipe_cli::get_unused_port().expect("Not available port");
         println!("Server started on port {}", port);
-        rusty_pipe_cli::run_server(port);
+        rusty_pipe_cli::run_server(port, sink);
     } else {
+        if sink.as_ref().map_or(false, Sink::writes_to_stdout) {
+            // The samples would be mixed with the terminal UI.
+            return Err(failure::err_msg(
+                "The pcm sink needs a path outside of server mode, use pcm:<path>",
+            ));
+        }
         async_std::task::block_on(async {
             let (tx1, rx1) = futures::channel::mpsc::channel(2);
             let (tx2, rx2) = futures::channel::mpsc::channel(2);
@@ -53,7 +73,7 @@ fn main() -> Result<(), Error> {
             //         .unwrap_or(3337),
             // );
             let cli_fut = rusty_pipe_cli::cli_ui::run_tui_pipe(rx1, tx2);
-            let player_fut = rusty_pipe_cli::r_player::run_audio_player(rx2, tx1);
+            let player_fut = rusty_pipe_cli::r_player::run_audio_player(rx2, tx1, sink);
             futures::join!(cli_fut, player_fut);
         });
     }
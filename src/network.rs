@@ -0,0 +1,332 @@
+//! The HTTP client every request goes through, the extractor's as well as the downloads', built
+//! once from the `network` section of the configuration.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use isahc::prelude::*;
+use serde::{Deserialize, Serialize};
+use surf::middleware::{Middleware, Next};
+
+use crate::config::Config;
+
+lazy_static::lazy_static! {
+    static ref CLIENT: Result<surf::Client, String> =
+        build_client(&Config::load().network).map_err(|err| err.to_string());
+}
+
+/// Network settings, from the configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NetworkOptions {
+    /// Proxy for every request, such as `http://host:8080` or `socks5h://host:1080`.
+    pub proxy: Option<String>,
+    /// Seconds to wait for a connection.
+    pub connect_timeout_secs: u64,
+    /// Seconds to wait for the response to a request, not counting the time to read its body.
+    pub timeout_secs: u64,
+    /// User agent sent with every request, the one of the HTTP library if not set.
+    pub user_agent: Option<String>,
+    /// Headers added to every request.
+    pub headers: HashMap<String, String>,
+    /// Cookies sent to YouTube from the start, as `name=value`, such as the cookie accepting its
+    /// consent page.
+    pub cookies: Vec<String>,
+}
+
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            connect_timeout_secs: 15,
+            timeout_secs: 30,
+            user_agent: None,
+            headers: HashMap::new(),
+            cookies: vec![],
+        }
+    }
+}
+
+/// Builds the shared client, failing if the network options are invalid. Called at startup, so
+/// that a bad proxy or header stops the program instead of every request bypassing it.
+pub fn init() -> Result<(), String> {
+    match &*CLIENT {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Invalid network options {}", err)),
+    }
+}
+
+/// The shared client. Clones of it share the connections and the cookies.
+pub fn client() -> surf::Client {
+    match &*CLIENT {
+        Ok(client) => client.clone(),
+        Err(err) => panic!("Invalid network options {}", err),
+    }
+}
+
+fn build_client(options: &NetworkOptions) -> Result<surf::Client, anyhow::Error> {
+    let mut builder =
+        HttpClient::builder().connect_timeout(Duration::from_secs(options.connect_timeout_secs));
+    if let Some(proxy) = &options.proxy {
+        let proxy = proxy
+            .parse::<isahc::http::Uri>()
+            .map_err(|err| anyhow::anyhow!("Invalid proxy {} {}", proxy, err))?;
+        builder = builder.proxy(Some(proxy));
+    }
+    let http_client = builder
+        .build()
+        .map_err(|err| anyhow::anyhow!("{:#?}", err))?;
+
+    let mut config = surf::Config::new()
+        .set_http_client(http_client::isahc::IsahcClient::from_client(http_client))
+        .set_timeout(Some(Duration::from_secs(options.timeout_secs)));
+    if let Some(user_agent) = &options.user_agent {
+        config = config
+            .add_header("User-Agent", user_agent.as_str())
+            .map_err(|err| anyhow::anyhow!("{}", err))?;
+    }
+    for (name, value) in options.headers.iter() {
+        config = config
+            .add_header(name.as_str(), value.as_str())
+            .map_err(|err| anyhow::anyhow!("Invalid header {} {}", name, err))?;
+    }
+    let client: surf::Client = config
+        .try_into()
+        .map_err(|err| anyhow::anyhow!("{}", err))?;
+    Ok(client.with(CookieJar::new(&options.cookies)))
+}
+
+/// Domain the configured cookies are sent to, along with its subdomains.
+const CONFIGURED_COOKIES_DOMAIN: &str = "youtube.com";
+
+/// Keeps the cookies servers set and sends them back to the hosts and paths they were set for.
+/// Expiry and the `Secure` and `HttpOnly` flags are not tracked, cookies last as long as the
+/// program.
+struct CookieJar {
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Lowercase, without a leading dot.
+    domain: String,
+    /// Only sent to `domain` itself, not to its subdomains, when the server set no `Domain`.
+    host_only: bool,
+    path: String,
+}
+
+impl Cookie {
+    /// Parses a `Set-Cookie` header received from `url`. None if it is malformed or set for a
+    /// domain `url` is not part of.
+    fn parse(set_cookie: &str, url: &surf::Url) -> Option<Self> {
+        let host = url.host_str()?.to_lowercase();
+        let mut parts = set_cookie.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let mut cookie = Self {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url.path()),
+        };
+        if cookie.name.is_empty() {
+            return None;
+        }
+        for attribute in parts {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_lowercase();
+                    if !domain_matches(&host, &domain) {
+                        return None;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                _ => {}
+            }
+        }
+        Some(cookie)
+    }
+
+    fn matches(&self, url: &surf::Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        let host_matches = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(&host, &self.domain)
+        };
+        host_matches && path_matches(url.path(), &self.path)
+    }
+}
+
+/// Whether `host` is `domain` or one of its subdomains.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain || (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'))
+}
+
+/// Whether a cookie set for `cookie_path` is sent with requests to `path`.
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// Path of a cookie set without `Path`, the directory of the request path.
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+impl CookieJar {
+    /// A jar holding `cookies`, as `name=value`, for YouTube.
+    fn new(cookies: &[String]) -> Self {
+        let jar = Self {
+            cookies: Mutex::new(vec![]),
+        };
+        let url = surf::Url::parse(&format!("https://{}/", CONFIGURED_COOKIES_DOMAIN))
+            .expect("Valid url");
+        for cookie in cookies {
+            let cookie = format!("{}; Domain={}", cookie, CONFIGURED_COOKIES_DOMAIN);
+            jar.store(&cookie, &url);
+        }
+        jar
+    }
+
+    /// Stores the cookie of a `Set-Cookie` header received from `url`, replacing the one of the
+    /// same name, domain and path.
+    fn store(&self, set_cookie: &str, url: &surf::Url) {
+        let cookie = match Cookie::parse(set_cookie, url) {
+            Some(cookie) => cookie,
+            None => {
+                log::warn!("Ignoring cookie {} from {}", set_cookie, url);
+                return;
+            }
+        };
+        let mut cookies = self.cookies.lock().expect("Cant lock cookies");
+        cookies.retain(|c| {
+            c.name != cookie.name || c.domain != cookie.domain || c.path != cookie.path
+        });
+        cookies.push(cookie);
+    }
+
+    /// Value of the `Cookie` header of a request to `url`, None if no cookie is for it.
+    fn header(&self, url: &surf::Url) -> Option<String> {
+        let cookies = self.cookies.lock().expect("Cant lock cookies");
+        let pairs = cookies
+            .iter()
+            .filter(|cookie| cookie.matches(url))
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect::<Vec<_>>();
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(pairs.join("; "))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for CookieJar {
+    async fn handle(
+        &self,
+        mut req: surf::Request,
+        client: surf::Client,
+        next: Next<'_>,
+    ) -> surf::Result<surf::Response> {
+        let url = req.url().clone();
+        if let Some(cookie) = self.header(&url) {
+            req.insert_header("Cookie", cookie.as_str());
+        }
+        let res = next.run(req, client).await?;
+        if let Some(values) = res.header("Set-Cookie") {
+            for value in values.iter() {
+                self.store(value.as_str(), &url);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> surf::Url {
+        surf::Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn configured_cookies_only_go_to_youtube() {
+        let jar = CookieJar::new(&["CONSENT=YES+1".to_string()]);
+        assert_eq!(
+            jar.header(&url("https://www.youtube.com/watch?v=x")),
+            Some("CONSENT=YES+1".to_string())
+        );
+        assert_eq!(
+            jar.header(&url("https://youtube.com/")),
+            Some("CONSENT=YES+1".to_string())
+        );
+        assert_eq!(
+            jar.header(&url("https://rr1.googlevideo.com/videoplayback")),
+            None
+        );
+        assert_eq!(jar.header(&url("https://notyoutube.com/")), None);
+    }
+
+    #[test]
+    fn cookie_without_domain_is_host_only() {
+        let jar = CookieJar::new(&[]);
+        jar.store("a=1; Path=/", &url("https://www.youtube.com/"));
+        assert_eq!(
+            jar.header(&url("https://www.youtube.com/")),
+            Some("a=1".to_string())
+        );
+        assert_eq!(jar.header(&url("https://m.youtube.com/")), None);
+    }
+
+    #[test]
+    fn cookie_for_another_domain_is_rejected() {
+        let jar = CookieJar::new(&[]);
+        jar.store("a=1; Domain=google.com", &url("https://www.youtube.com/"));
+        assert_eq!(jar.header(&url("https://www.google.com/")), None);
+    }
+
+    #[test]
+    fn cookie_is_scoped_to_its_path() {
+        let jar = CookieJar::new(&[]);
+        jar.store("a=1; Path=/api", &url("https://www.youtube.com/"));
+        assert_eq!(
+            jar.header(&url("https://www.youtube.com/api/x")),
+            Some("a=1".to_string())
+        );
+        assert_eq!(
+            jar.header(&url("https://www.youtube.com/api")),
+            Some("a=1".to_string())
+        );
+        assert_eq!(jar.header(&url("https://www.youtube.com/apix")), None);
+        assert_eq!(jar.header(&url("https://www.youtube.com/")), None);
+    }
+
+    #[test]
+    fn same_cookie_is_replaced() {
+        let jar = CookieJar::new(&[]);
+        jar.store("a=1", &url("https://www.youtube.com/"));
+        jar.store("a=2", &url("https://www.youtube.com/"));
+        assert_eq!(
+            jar.header(&url("https://www.youtube.com/")),
+            Some("a=2".to_string())
+        );
+    }
+}
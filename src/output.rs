@@ -7,16 +7,42 @@
 
 //! Platform-dependant Audio Outputs
 
+use std::path::PathBuf;
 use std::result;
+use std::str::FromStr;
 
 use symphonia::core::audio::{AudioBufferRef, SignalSpec};
 use symphonia::core::units::Duration;
 
+use crate::dsp::resample::ResampleQuality;
+
 pub trait AudioOutput {
     fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()>;
     fn flush(&mut self);
+    /// Drops the samples written but not played yet.
+    fn clear(&mut self);
+    /// Sets the software gain, where 1.0 is unity and 1.5 the maximum.
+    fn set_volume(&mut self, volume: f32);
+    fn set_mute(&mut self, mute: bool);
+    /// Sets the playback rate, stretching time without changing the pitch.
+    fn set_speed(&mut self, speed: f32);
+    /// Length of the audio written so far, in seconds at normal speed.
+    fn written_secs(&self) -> f64;
+    /// How much of the written audio was played by the device, on the same clock as
+    /// `written_secs`.
+    fn played_secs(&self) -> f64;
+    /// Accepts samples at `rate` from now on. Returns false if the output cant convert them and
+    /// has to be opened again for the rate.
+    fn set_input_rate(&mut self, rate: u32) -> bool;
+    fn set_resample_quality(&mut self, quality: ResampleQuality);
+    /// Writes out the samples the rate conversion still holds back, once the last samples of the
+    /// stream were written.
+    fn end_stream(&mut self) -> Result<()>;
 }
 
+/// Highest software gain accepted by `AudioOutput::set_volume`.
+pub const MAX_VOLUME: f32 = 1.5;
+
 #[allow(dead_code)]
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
@@ -24,12 +50,88 @@ pub enum AudioOutputError {
     OpenStreamError,
     PlayStreamError,
     StreamClosedError,
+    /// The device was unplugged or otherwise went away while playing.
+    DeviceNotAvailable,
 }
 
 pub type Result<T> = result::Result<T, AudioOutputError>;
 
+/// Where the player sends its audio.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+    /// The audio device, through cpal.
+    Device,
+    /// A 32 bit float WAV file.
+    Wav(PathBuf),
+    /// Raw interleaved 32 bit float little endian samples, written to stdout when no path is
+    /// given, which only the server allows. The path may be a FIFO.
+    Pcm(Option<PathBuf>),
+    /// Discards the samples, only the clock advances.
+    Null,
+}
+
+impl Default for Sink {
+    fn default() -> Self {
+        Sink::Device
+    }
+}
+
+impl Sink {
+    /// Whether the samples go to stdout, which the terminal UI also draws on.
+    pub fn writes_to_stdout(&self) -> bool {
+        *self == Sink::Pcm(None)
+    }
+}
+
+impl FromStr for Sink {
+    type Err = String;
+
+    /// Parses `device`, `null`, `wav:<path>`, `pcm` or `pcm:-` for stdout, and `pcm:<path>`.
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "device" => Ok(Sink::Device),
+            None if s == "null" => Ok(Sink::Null),
+            None if s == "pcm" => Ok(Sink::Pcm(None)),
+            Some(("pcm", "-")) => Ok(Sink::Pcm(None)),
+            Some(("pcm", path)) => Ok(Sink::Pcm(Some(path.into()))),
+            Some(("wav", path)) if !path.is_empty() => Ok(Sink::Wav(path.into())),
+            _ => Err(format!(
+                "Unknown sink {}, expected device, null, wav:<path> or pcm[:<path>]",
+                s
+            )),
+        }
+    }
+}
+
+/// Time it takes the gain to ramp across the full volume range, in seconds.
+const GAIN_RAMP_SECS: f32 = 0.02;
+
+/// Scales interleaved `samples` by `target`, moving `gain` towards it a little every frame
+/// instead of jumping to it.
+fn ramp_gain(samples: &mut [f32], gain: &mut f32, target: f32, rate: u32, channels: usize) {
+    if *gain == target && target == 1.0 {
+        return;
+    }
+    let step = MAX_VOLUME / (GAIN_RAMP_SECS * rate as f32);
+    for frame in samples.chunks_mut(channels.max(1)) {
+        if *gain < target {
+            *gain = (*gain + step).min(target);
+        } else if *gain > target {
+            *gain = (*gain - step).max(target);
+        }
+        frame.iter_mut().for_each(|s| *s *= *gain);
+    }
+}
+
 mod cpal {
     use super::{AudioOutput, AudioOutputError, Result};
+    use crate::dsp::channels::ChannelMap;
+    use crate::dsp::resample::{ResampleQuality, Resampler};
+    use crate::dsp::stretch::TimeStretcher;
+
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
 
     use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
     use symphonia::core::conv::ConvertibleSample;
@@ -54,7 +156,7 @@ mod cpal {
         }
 
         fn from_f32(n: f32) -> Self {
-            n
+            n.max(-1.0).min(1.0)
         }
     }
     impl AudioOutputSample for i16 {
@@ -66,18 +168,23 @@ mod cpal {
             n as i16
         }
     }
+    // Unsigned samples are centered on 32768, shift them so that gain scales around silence.
     impl AudioOutputSample for u16 {
         fn to_f32(&self) -> f32 {
-            (*self) as f32
+            (*self) as f32 - 32768.0
         }
 
         fn from_f32(n: f32) -> Self {
-            n as u16
+            (n + 32768.0) as u16
         }
     }
 
     impl CpalAudioOutput {
-        pub fn try_open(spec: SignalSpec, duration: Duration) -> Result<Box<dyn AudioOutput>> {
+        pub fn try_open(
+            spec: SignalSpec,
+            duration: Duration,
+            device_name: Option<&str>,
+        ) -> Result<Box<dyn AudioOutput>> {
             // Get default host.
             log::debug!("Get default host");
             let host = cpal::default_host();
@@ -93,151 +200,116 @@ mod cpal {
                 "Output devices {:#?}",
                 host.output_devices().map(|d| d.count())
             );
-            // Get the default audio output device.
-            let devices = match host.output_devices() {
-                Ok(device) => {
-                    log::debug!("Got devices ");
-                    device
-                }
-                _ => {
-                    error!("failed to get default audio output device");
+            let device = match find_device(&host, device_name) {
+                Some(device) => device,
+                None => {
+                    error!("failed to get audio output device");
                     return Err(AudioOutputError::OpenStreamError);
                 }
             };
+            log::info!("Using output device {:?}", device.name());
 
             let mut need_h_sam = false;
-            // log::info!("found devices {:#?}",devices);
 
-            let (config, device) = {
-                log::debug!("create config and devices");
+            let config = {
+                log::debug!("create config");
                 let mut c = None;
-                let mut d = None;
                 let mut min_sample_rate = u32::MAX;
                 let mut max_sample_rate = 0;
-                let mut close_d = None;
+                let mut close_c = None;
                 let mut close_val = std::i64::MAX;
-                log::debug!("Loop devices");
-                let ignore_nonf = {
-                    let mut ig = false;
-                    if let Ok(devices) = host.devices() {
-                        for d in devices {
-                            if let Ok(conf) = d.supported_output_configs() {
-                                for c in conf {
-                                    if c.sample_format() == SampleFormat::F32 {
-                                        ig = true;
-                                        break;
-                                    }
-                                }
-                                if ig {
-                                    break;
-                                }
-                            }
-                        }
+                log::info!("Get supported configs");
+                let configs = match device.supported_output_configs() {
+                    Ok(configs) => configs.collect::<Vec<_>>(),
+                    Err(err) => {
+                        error!("failed to get audio output device config: {}", err);
+                        vec![]
                     }
-                    ig
                 };
-                if let Some(default_output_device) = host.default_output_device(){
-                    for (device_index, device) in vec![default_output_device].into_iter().enumerate() {
-                        log::info!("Get supported configs");
-                        match device.supported_output_configs() {
-                            Ok(configs) => {
-                                log::debug!("Received configs");
-                                for config in configs {
-                                    log::debug!(
-                                        "Config rates {:#?} - {:#?}, format {:#?}",
-                                        config.min_sample_rate(),
-                                        config.max_sample_rate(),
-                                        config.sample_format()
-                                    );
-                                    if ignore_nonf && config.sample_format() != SampleFormat::F32 {
-                                        continue;
-                                    }
-                                    if spec.rate <= config.max_sample_rate().0
-                                        && spec.rate >= config.min_sample_rate().0
-                                    {
-                                        log::debug!("Setting config");
-                                        c = Some(config.with_sample_rate(cpal::SampleRate(spec.rate)));
-    
-                                        break;
-                                    }
-                                    if config.min_sample_rate().0 < min_sample_rate {
-                                        min_sample_rate = config.min_sample_rate().0;
-                                    }
-                                    if config.max_sample_rate().0 > max_sample_rate {
-                                        max_sample_rate = config.max_sample_rate().0;
-                                    }
-    
-                                    log::info!(
-                                        "Current Confir min {:#?} max {:#?} format {:#?}",
-                                        config.min_sample_rate(),
-                                        config.max_sample_rate(),
-                                        config.sample_format()
-                                    );
-                                    let min_diff = config.min_sample_rate().0 as i64 - spec.rate as i64;
-                                    let max_diff = config.max_sample_rate().0 as i64 - spec.rate as i64;
-                                    let new_close_val = std::cmp::min(min_diff.abs(), max_diff.abs());
-                                    if new_close_val < close_val {
-                                        log::info!("Old close val {}", close_val);
-                                        log::info!("New close val {}", new_close_val);
-                                        close_val = new_close_val;
-                                        let rate = {
-                                            if close_val == max_diff.abs() {
-                                                config.max_sample_rate()
-                                            } else {
-                                                config.min_sample_rate()
-                                            }
-                                        };
-                                        close_d = Some((config.with_sample_rate(rate), device_index));
-                                    }
-                                }
-                                if c.is_some() {
-                                    d = Some(device);
-                                    break;
-                                }
-                            }
-                            Err(err) => {
-                                error!("failed to get default audio output device config: {}", err);
-                                debug!(
-                                    "Supported configs {:#?}",
-                                    device.supported_output_configs().map(|c| c.count())
-                                )
+                // Open the track's channels if the device has them, otherwise stereo, otherwise
+                // whatever it has. The track is mixed to the channels opened.
+                let wanted = spec.channels.count() as cpal::ChannelCount;
+                let channels = [wanted, 2]
+                    .iter()
+                    .copied()
+                    .find(|ch| configs.iter().any(|c| c.channels() == *ch))
+                    .or_else(|| configs.first().map(|c| c.channels()))
+                    .unwrap_or(wanted);
+                let configs = configs
+                    .into_iter()
+                    .filter(|c| c.channels() == channels)
+                    .collect::<Vec<_>>();
+                // Prefer f32 configs when the device has any.
+                let ignore_nonf = configs
+                    .iter()
+                    .any(|c| c.sample_format() == SampleFormat::F32);
+                for config in configs {
+                    log::debug!(
+                        "Config rates {:#?} - {:#?}, format {:#?}",
+                        config.min_sample_rate(),
+                        config.max_sample_rate(),
+                        config.sample_format()
+                    );
+                    if ignore_nonf && config.sample_format() != SampleFormat::F32 {
+                        continue;
+                    }
+                    if spec.rate <= config.max_sample_rate().0
+                        && spec.rate >= config.min_sample_rate().0
+                    {
+                        log::debug!("Setting config");
+                        c = Some(config.with_sample_rate(cpal::SampleRate(spec.rate)));
+                        break;
+                    }
+                    if config.min_sample_rate().0 < min_sample_rate {
+                        min_sample_rate = config.min_sample_rate().0;
+                    }
+                    if config.max_sample_rate().0 > max_sample_rate {
+                        max_sample_rate = config.max_sample_rate().0;
+                    }
+
+                    log::info!(
+                        "Current Confir min {:#?} max {:#?} format {:#?}",
+                        config.min_sample_rate(),
+                        config.max_sample_rate(),
+                        config.sample_format()
+                    );
+                    let min_diff = config.min_sample_rate().0 as i64 - spec.rate as i64;
+                    let max_diff = config.max_sample_rate().0 as i64 - spec.rate as i64;
+                    let new_close_val = std::cmp::min(min_diff.abs(), max_diff.abs());
+                    if new_close_val < close_val {
+                        log::info!("Old close val {}", close_val);
+                        log::info!("New close val {}", new_close_val);
+                        close_val = new_close_val;
+                        let rate = {
+                            if close_val == max_diff.abs() {
+                                config.max_sample_rate()
+                            } else {
+                                config.min_sample_rate()
                             }
                         };
+                        close_c = Some(config.with_sample_rate(rate));
                     }
-                    
                 }
-                log::debug!("set default device");
                 if c.is_none() {
                     log::debug!("No suitable config set");
-                    if let Some((config, device)) = close_d {
-                        log::info!("Setting close device");
-                        if let Ok(mut devices) = host.output_devices() {
-                            if let Some(device) = devices.nth(device) {
-                                let config:SupportedStreamConfig = config;
-                                need_h_sam = config.sample_rate().0 != spec.rate;
-                                c = Some(config);
-                                d = Some(device);
-                                
-                            }
-                        }
-                    } else if let Some(device) = host.default_output_device() {
-                        log::info!("Using default device");
-                        
-                        if let Ok(config) = device.default_output_config() {
-                            log::debug!("Use default config");
-                            need_h_sam = config.sample_rate().0 != spec.rate;
-                            c = Some(config);
-                            d = Some(device);
-                        }else{
-                            log::warn!("No defaullt config for default device");
-                        }
+                    if let Some(config) = close_c {
+                        log::info!("Setting closest config");
+                        let config: SupportedStreamConfig = config;
+                        need_h_sam = config.sample_rate().0 != spec.rate;
+                        c = Some(config);
+                    } else if let Ok(config) = device.default_output_config() {
+                        log::debug!("Use default config");
+                        need_h_sam = config.sample_rate().0 != spec.rate;
+                        c = Some(config);
+                    } else {
+                        log::warn!("No default config for device");
                     }
                 }
-                log::debug!("Return device and config");
+                log::debug!("Return config");
                 if let Some(out) = c {
-                    (out, d.unwrap())
+                    out
                 } else {
-                    log::error!("No device and config found");
+                    log::error!("No config found");
                     log::info!(
                         "Min -> {} Max -> {} Spec {}",
                         min_sample_rate,
@@ -249,6 +321,7 @@ mod cpal {
             };
 
             log::debug!("Supported config {:#?}", config);
+            let channels = config.channels() as usize;
             // Select proper playback routine based on sample format.
             let rate = if need_h_sam {
                 config.sample_rate().0
@@ -258,30 +331,79 @@ mod cpal {
             match config.sample_format() {
                 cpal::SampleFormat::F32 => {
                     log::debug!("Open f32");
-                    CpalAudioOutputImpl::<f32>::try_open(spec, duration, &device, rate)
+                    CpalAudioOutputImpl::<f32>::try_open(spec, duration, &device, rate, channels)
                 }
                 cpal::SampleFormat::I16 => {
                     log::debug!("Open i16");
-                    CpalAudioOutputImpl::<i16>::try_open(spec, duration, &device, rate)
+                    CpalAudioOutputImpl::<i16>::try_open(spec, duration, &device, rate, channels)
                 }
                 cpal::SampleFormat::U16 => {
                     log::debug!("Open u16");
-                    CpalAudioOutputImpl::<u16>::try_open(spec, duration, &device, rate)
+                    CpalAudioOutputImpl::<u16>::try_open(spec, duration, &device, rate, channels)
                 }
             }
         }
     }
 
+    pub fn device_names() -> Vec<String> {
+        match cpal::default_host().output_devices() {
+            Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+            Err(err) => {
+                error!("failed to list audio output devices: {}", err);
+                vec![]
+            }
+        }
+    }
+
+    pub fn default_device_name() -> Option<String> {
+        cpal::default_host()
+            .default_output_device()
+            .and_then(|d| d.name().ok())
+    }
+
+    /// The output device called `name`, or the default device when there is none by that name.
+    fn find_device(host: &cpal::Host, name: Option<&str>) -> Option<cpal::Device> {
+        if let Some(name) = name {
+            let found = host.output_devices().ok().and_then(|mut devices| {
+                devices.find(|d| d.name().map(|n| n == name).unwrap_or(false))
+            });
+            match found {
+                Some(device) => return Some(device),
+                None => log::warn!("No output device named {}, using the default", name),
+            }
+        }
+        host.default_output_device()
+    }
+
     struct CpalAudioOutputImpl<T: AudioOutputSample>
     where
         T: AudioOutputSample,
     {
+        ring_buf: SpscRb<T>,
         ring_buf_producer: rb::Producer<T>,
         sample_buf: SampleBuffer<T>,
         stream: cpal::Stream,
         rate: u32,
         original_rate: u32,
+        /// Channels of the device, the track's channels are mapped to them.
         channels: usize,
+        channel_map: ChannelMap,
+        volume: f32,
+        muted: bool,
+        /// Gain applied to the last written frame, ramped towards the target to avoid clicks.
+        gain: f32,
+        resampler: Resampler,
+        stretcher: TimeStretcher,
+        /// Set by the stream when its device goes away.
+        device_lost: Arc<AtomicBool>,
+        /// Frames read from the ring buffer by the device callback.
+        played_frames: Arc<AtomicU64>,
+        /// Frames written to the ring buffer.
+        written_frames: u64,
+        written_secs: f64,
+        /// `written_frames` and `written_secs` after each write not fully played yet, preceded by
+        /// the last one that was.
+        clock: VecDeque<(u64, f64)>,
     }
 
     impl<T: AudioOutputSample> CpalAudioOutputImpl<T> {
@@ -290,10 +412,11 @@ mod cpal {
             duration: Duration,
             device: &cpal::Device,
             rate: u32,
+            channels: usize,
         ) -> Result<Box<dyn AudioOutput>> {
             // Output audio stream config.
             let config = cpal::StreamConfig {
-                channels: spec.channels.count() as cpal::ChannelCount,
+                channels: channels as cpal::ChannelCount,
                 sample_rate: cpal::SampleRate(rate),
                 buffer_size: cpal::BufferSize::Default,
             };
@@ -303,16 +426,27 @@ mod cpal {
             let ring_buf = SpscRb::new(8 * 1024);
             let (ring_buf_producer, ring_buf_consumer) = (ring_buf.producer(), ring_buf.consumer());
 
+            let played_frames = Arc::new(AtomicU64::new(0));
+            let played = played_frames.clone();
+            let device_lost = Arc::new(AtomicBool::new(false));
+            let lost = device_lost.clone();
+
             let stream_result = device.build_output_stream(
                 &config,
                 move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
                     // Write out as many samples as possible from the ring buffer to the audio
                     // output.
                     let written = ring_buf_consumer.read(data).unwrap_or(0);
+                    played.fetch_add((written / channels) as u64, Ordering::Relaxed);
                     // Mute any remaining samples.
                     data[written..].iter_mut().for_each(|s| *s = T::MID);
                 },
-                move |err| error!("audio output error: {}", err),
+                move |err| {
+                    error!("audio output error: {}", err);
+                    if let cpal::StreamError::DeviceNotAvailable = err {
+                        lost.store(true, Ordering::Relaxed);
+                    }
+                },
             );
 
             if let Err(err) = stream_result {
@@ -333,12 +467,24 @@ mod cpal {
             let sample_buf = SampleBuffer::<T>::new(duration, spec);
 
             Ok(Box::new(CpalAudioOutputImpl {
+                ring_buf,
                 ring_buf_producer,
                 sample_buf,
                 stream,
                 rate,
                 original_rate: spec.rate,
-                channels: spec.channels.count(),
+                channels,
+                channel_map: ChannelMap::new(spec.channels, channels),
+                volume: 1.0,
+                muted: false,
+                gain: 1.0,
+                resampler: Resampler::new(ResampleQuality::default(), spec.rate, rate, channels),
+                stretcher: TimeStretcher::new(rate, channels),
+                device_lost,
+                played_frames,
+                written_frames: 0,
+                written_secs: 0.0,
+                clock: vec![(0, 0.0)].into(),
             }))
         }
     }
@@ -349,76 +495,590 @@ mod cpal {
             if decoded.frames() == 0 {
                 return Ok(());
             }
+            let frames = decoded.frames();
 
             // Audio samples must be interleaved for cpal. Interleave the samples in the audio
             // buffer into the sample buffer.
             self.sample_buf.copy_interleaved_ref(decoded);
 
-            let mut i = 0;
-            let converted_samples = {
-                if self.rate != self.original_rate {
-                    log::debug!("trying to create sample rate converter");
-                    let converter = samplerate::Samplerate::new(
-                        samplerate::ConverterType::SincBestQuality,
-                        self.original_rate,
-                        self.rate,
-                        self.channels,
-                    )
-                    .expect("Cant create converter");
-                    log::debug!("trying to convert to new sample rate");
-                    let new_sample = converter
-                        .process_last(
-                            &self
-                                .sample_buf
-                                .samples()
-                                .iter()
-                                .map(|f| AudioOutputSample::to_f32(f))
-                                .collect::<Vec<_>>(),
-                        )
-                        .expect("Cant convert");
-                    let new_sample = new_sample
-                        .iter()
-                        .map(|f| T::from_f32(*f))
-                        .collect::<Vec<_>>();
-                    log::info!(
-                        "Converted from {} -> {}, Rate {} -> {}",
-                        &self.sample_buf.samples().len(),
-                        new_sample.len(),
-                        self.original_rate,
-                        self.rate
-                    );
-                    new_sample
-                } else {
-                    (self.sample_buf.samples())
-                        .iter()
-                        .map(|f| T::from_f32(AudioOutputSample::to_f32(f)))
-                        .collect::<Vec<_>>()
-                }
+            let samples = self
+                .sample_buf
+                .samples()
+                .iter()
+                .map(|f| AudioOutputSample::to_f32(f))
+                .collect::<Vec<_>>();
+            let samples = if self.channel_map.is_identity() {
+                samples
+            } else {
+                self.channel_map.apply(&samples)
             };
+            let samples = self.resampler.process(samples);
+            self.written_secs += frames as f64 / self.original_rate as f64;
+            self.write_converted(&samples)
+        }
+
+        fn flush(&mut self) {
+            // Flush is best-effort, ignore the returned result.
+            let _ = self.stream.pause();
+        }
+
+        fn clear(&mut self) {
+            self.ring_buf.clear();
+            self.resampler.reset();
+            self.stretcher.reset();
+            // Nothing is waiting anymore, the clock continues from what was played.
+            self.written_frames = self.played_frames.load(Ordering::Relaxed);
+            self.clock.clear();
+            self.clock
+                .push_back((self.written_frames, self.written_secs));
+        }
+
+        fn set_volume(&mut self, volume: f32) {
+            self.volume = volume.max(0.0).min(super::MAX_VOLUME);
+        }
+
+        fn set_mute(&mut self, mute: bool) {
+            self.muted = mute;
+        }
+
+        fn set_speed(&mut self, speed: f32) {
+            self.stretcher.set_speed(speed);
+        }
+
+        fn written_secs(&self) -> f64 {
+            self.written_secs
+        }
+
+        fn played_secs(&self) -> f64 {
+            // Interpolate within the write being played, frames of the output rate map linearly
+            // to the seconds written as long as the speed did not change in between.
+            let played = self.played_frames.load(Ordering::Relaxed);
+            let mut marks = self.clock.iter();
+            let mut last = match marks.next() {
+                Some(mark) => *mark,
+                None => return 0.0,
+            };
+            for &(frames, secs) in marks {
+                if frames > played {
+                    let ratio = played.saturating_sub(last.0) as f64 / (frames - last.0) as f64;
+                    return last.1 + (secs - last.1) * ratio;
+                }
+                last = (frames, secs);
+            }
+            last.1
+        }
+
+        fn set_input_rate(&mut self, rate: u32) -> bool {
+            self.original_rate = rate;
+            self.resampler.set_input_rate(rate);
+            true
+        }
+
+        fn set_resample_quality(&mut self, quality: ResampleQuality) {
+            self.resampler.set_quality(quality);
+        }
+
+        fn end_stream(&mut self) -> Result<()> {
+            let samples = self.resampler.flush();
+            self.write_converted(&samples)
+        }
+    }
+
+    impl<T: AudioOutputSample> CpalAudioOutputImpl<T> {
+        /// Stretches, scales and writes interleaved `samples` at the output rate to the ring
+        /// buffer, and notes on the clock when they end.
+        fn write_converted(&mut self, samples: &[f32]) -> Result<()> {
+            if samples.is_empty() {
+                return Ok(());
+            }
+            // Stretch at the output rate, once the samples are converted to it.
+            let mut samples = self.stretcher.process(samples);
+            self.apply_gain(&mut samples);
+            let converted_samples = samples.iter().map(|f| T::from_f32(*f)).collect::<Vec<_>>();
             // Write out all samples in the sample buffer to the ring buffer.
+            let mut i = 0;
             while i < converted_samples.len() {
                 let writeable_samples = &converted_samples[i..];
 
-                // Write as many samples as possible to the ring buffer. This blocks until some
-                // samples are written or the consumer has been destroyed (None is returned).
-                if let Some(written) = self.ring_buf_producer.write_blocking(writeable_samples) {
-                    i += written;
-                } else {
-                    // Consumer destroyed, return an error.
-                    return Err(AudioOutputError::StreamClosedError);
+                // Write as many samples as possible to the ring buffer. When it is full wait for
+                // the device to play some, unless the device is gone and never will.
+                match self.ring_buf_producer.write(writeable_samples) {
+                    Ok(written) => i += written,
+                    Err(_) => {
+                        if self.device_lost.load(Ordering::Relaxed) {
+                            return Err(AudioOutputError::DeviceNotAvailable);
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                    }
                 }
             }
 
+            self.written_frames += (converted_samples.len() / self.channels) as u64;
+            self.clock
+                .push_back((self.written_frames, self.written_secs));
+            let played = self.played_frames.load(Ordering::Relaxed);
+            while self.clock.len() > 1 && self.clock[1].0 <= played {
+                self.clock.pop_front();
+            }
+
+            Ok(())
+        }
+
+        /// Scales interleaved `samples` by the volume.
+        fn apply_gain(&mut self, samples: &mut [f32]) {
+            let target = if self.muted { 0.0 } else { self.volume };
+            super::ramp_gain(samples, &mut self.gain, target, self.rate, self.channels);
+        }
+    }
+}
+
+mod sink {
+    use super::{AudioOutput, AudioOutputError, ResampleQuality, Result, Sink};
+    use crate::dsp::channels::ChannelMap;
+    use crate::dsp::resample::Resampler;
+    use crate::dsp::stretch::TimeStretcher;
+
+    use std::collections::HashMap;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
+    use symphonia::core::units::Duration;
+
+    /// Size of the WAV header written ahead of the samples.
+    const WAV_HEADER_LEN: u32 = 44;
+
+    lazy_static::lazy_static! {
+        /// Files opened by sinks during this run, by path. The player opens the sink again when
+        /// tracks of another signal spec follow each other, those outputs append to the file.
+        static ref FILES: Mutex<HashMap<PathBuf, Arc<Mutex<SinkFile>>>> =
+            Mutex::new(HashMap::new());
+    }
+
+    /// A WAV or raw PCM file shared by the outputs opened on it. It keeps the rate and channel
+    /// count it was created with, the samples of later tracks are converted to them.
+    struct SinkFile {
+        out: BufWriter<File>,
+        wav: bool,
+        rate: u32,
+        channels: usize,
+        /// Bytes of samples written.
+        data_len: u32,
+    }
+
+    impl SinkFile {
+        /// The file at `path`, created for samples of `rate` and `channels` if no output opened
+        /// it yet.
+        fn open(path: &Path, wav: bool, rate: u32, channels: usize) -> Result<Arc<Mutex<Self>>> {
+            let mut files = FILES.lock().expect("Cant lock sink files");
+            if let Some(file) = files.get(path) {
+                return Ok(file.clone());
+            }
+            let mut out = BufWriter::new(create(path)?);
+            if wav {
+                write_wav_header(&mut out, rate, channels as u16, 0).map_err(|err| {
+                    log::error!("Cant write WAV header {:#?}", err);
+                    AudioOutputError::OpenStreamError
+                })?;
+            }
+            let file = Arc::new(Mutex::new(Self {
+                out,
+                wav,
+                rate,
+                channels,
+                data_len: 0,
+            }));
+            files.insert(path.to_path_buf(), file.clone());
+            Ok(file)
+        }
+
+        fn write_samples(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.out.write_all(bytes)?;
+            self.data_len = self.data_len.saturating_add(bytes.len() as u32);
+            Ok(())
+        }
+
+        /// Writes out everything buffered, with the sizes in the WAV header updated for the
+        /// samples so far.
+        fn finish(&mut self) -> io::Result<()> {
+            if self.wav {
+                let position = self.out.seek(SeekFrom::Current(0))?;
+                self.out.seek(SeekFrom::Start(0))?;
+                write_wav_header(
+                    &mut self.out,
+                    self.rate,
+                    self.channels as u16,
+                    self.data_len,
+                )?;
+                self.out.seek(SeekFrom::Start(position))?;
+            }
+            self.out.flush()
+        }
+    }
+
+    enum Target {
+        File(Arc<Mutex<SinkFile>>),
+        Stdout(io::Stdout),
+        Null,
+    }
+
+    /// Output writing the samples somewhere other than an audio device. Nothing is buffered on
+    /// the way, so the samples count as played as soon as they are written.
+    pub struct SinkOutput {
+        target: Target,
+        sample_buf: SampleBuffer<f32>,
+        /// Rate of the samples written out.
+        rate: u32,
+        /// Rate of the decoded samples.
+        original_rate: u32,
+        channels: usize,
+        channel_map: ChannelMap,
+        resampler: Resampler,
+        volume: f32,
+        muted: bool,
+        gain: f32,
+        stretcher: TimeStretcher,
+        written_secs: f64,
+    }
+
+    impl SinkOutput {
+        /// Opens `sink` for samples of `spec`. Files are created by the first output opened on
+        /// them and appended to by the next ones, in the rate and channel count of the first.
+        pub fn try_open(
+            sink: &Sink,
+            spec: SignalSpec,
+            duration: Duration,
+        ) -> Result<Box<dyn AudioOutput>> {
+            let target = match sink {
+                Sink::Wav(path) => Target::File(SinkFile::open(
+                    path,
+                    true,
+                    spec.rate,
+                    spec.channels.count(),
+                )?),
+                Sink::Pcm(Some(path)) => Target::File(SinkFile::open(
+                    path,
+                    false,
+                    spec.rate,
+                    spec.channels.count(),
+                )?),
+                Sink::Pcm(None) => Target::Stdout(io::stdout()),
+                Sink::Null => Target::Null,
+                Sink::Device => return Err(AudioOutputError::OpenStreamError),
+            };
+            let (rate, channels) = match &target {
+                Target::File(file) => {
+                    let file = file.lock().expect("Cant lock sink file");
+                    (file.rate, file.channels)
+                }
+                _ => (spec.rate, spec.channels.count()),
+            };
+            Ok(Box::new(SinkOutput {
+                target,
+                sample_buf: SampleBuffer::<f32>::new(duration, spec),
+                rate,
+                original_rate: spec.rate,
+                channels,
+                channel_map: ChannelMap::new(spec.channels, channels),
+                resampler: Resampler::new(ResampleQuality::default(), spec.rate, rate, channels),
+                volume: 1.0,
+                muted: false,
+                gain: 1.0,
+                stretcher: TimeStretcher::new(rate, channels),
+                written_secs: 0.0,
+            }))
+        }
+
+        /// Stretches, scales and writes interleaved `samples` at the output rate.
+        fn write_converted(&mut self, samples: &[f32]) -> Result<()> {
+            let mut samples = self.stretcher.process(samples);
+            let target = if self.muted { 0.0 } else { self.volume };
+            super::ramp_gain(
+                &mut samples,
+                &mut self.gain,
+                target,
+                self.rate,
+                self.channels,
+            );
+            self.write_samples(&samples).map_err(|err| {
+                log::error!("Cant write samples {:#?}", err);
+                AudioOutputError::StreamClosedError
+            })
+        }
+
+        fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+            let bytes = samples
+                .iter()
+                .flat_map(|s| s.to_le_bytes())
+                .collect::<Vec<_>>();
+            match &mut self.target {
+                Target::File(file) => file
+                    .lock()
+                    .expect("Cant lock sink file")
+                    .write_samples(&bytes)?,
+                Target::Stdout(out) => out.write_all(&bytes)?,
+                Target::Null => {}
+            }
+            Ok(())
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            match &mut self.target {
+                Target::File(file) => file.lock().expect("Cant lock sink file").finish(),
+                Target::Stdout(out) => out.flush(),
+                Target::Null => Ok(()),
+            }
+        }
+    }
+
+    impl AudioOutput for SinkOutput {
+        fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+            if decoded.frames() == 0 {
+                return Ok(());
+            }
+            let frames = decoded.frames();
+            self.sample_buf.copy_interleaved_ref(decoded);
+            let samples = self.channel_map.apply(self.sample_buf.samples());
+            let samples = self.resampler.process(samples);
+            self.write_converted(&samples)?;
+            self.written_secs += frames as f64 / self.original_rate as f64;
             Ok(())
         }
 
         fn flush(&mut self) {
-            // Flush is best-effort, ignore the returned result.
-            let _ = self.stream.pause();
+            if let Err(err) = self.finish() {
+                log::warn!("Cant flush samples {:#?}", err);
+            }
+        }
+
+        fn clear(&mut self) {
+            self.resampler.reset();
+            self.stretcher.reset();
+        }
+
+        fn set_volume(&mut self, volume: f32) {
+            self.volume = volume.max(0.0).min(super::MAX_VOLUME);
+        }
+
+        fn set_mute(&mut self, mute: bool) {
+            self.muted = mute;
+        }
+
+        fn set_speed(&mut self, speed: f32) {
+            self.stretcher.set_speed(speed);
+        }
+
+        fn written_secs(&self) -> f64 {
+            self.written_secs
+        }
+
+        fn played_secs(&self) -> f64 {
+            self.written_secs
+        }
+
+        fn set_input_rate(&mut self, rate: u32) -> bool {
+            self.original_rate = rate;
+            self.resampler.set_input_rate(rate);
+            true
+        }
+
+        fn set_resample_quality(&mut self, quality: ResampleQuality) {
+            self.resampler.set_quality(quality);
+        }
+
+        fn end_stream(&mut self) -> Result<()> {
+            let samples = self.resampler.flush();
+            self.write_converted(&samples)?;
+            // Nothing follows for now, the file can be read back, the WAV header included.
+            self.flush();
+            Ok(())
         }
     }
+
+    impl Drop for SinkOutput {
+        fn drop(&mut self) {
+            self.flush();
+        }
+    }
+
+    /// Opens `path` for writing, which also works for a FIFO another program reads from.
+    fn create(path: &Path) -> Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|err| {
+                log::error!("Cant open {:?} {:#?}", path, err);
+                AudioOutputError::OpenStreamError
+            })
+    }
+
+    /// Writes the header of a 32 bit float WAV file holding `data_len` bytes of samples.
+    fn write_wav_header<W: Write>(
+        out: &mut W,
+        rate: u32,
+        channels: u16,
+        data_len: u32,
+    ) -> io::Result<()> {
+        let block_align = channels * 4;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(WAV_HEADER_LEN - 8).saturating_add(data_len).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // IEEE float samples.
+        out.write_all(&3u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&rate.to_le_bytes())?;
+        out.write_all(&(rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&32u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&data_len.to_le_bytes())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        use std::borrow::Cow;
+
+        use symphonia::core::audio::{AudioBuffer, Channels, Signal};
+
+        fn spec(rate: u32, channels: Channels) -> SignalSpec {
+            SignalSpec::new(rate, channels)
+        }
+
+        /// Writes `frames` frames of `value` on every channel.
+        fn write(output: &mut dyn AudioOutput, spec: SignalSpec, frames: usize, value: f32) {
+            let mut buf = AudioBuffer::<f32>::new(frames as u64, spec);
+            buf.render_reserved(Some(frames));
+            for ch in 0..spec.channels.count() {
+                buf.chan_mut(ch).iter_mut().for_each(|s| *s = value);
+            }
+            output
+                .write(AudioBufferRef::F32(Cow::Borrowed(&buf)))
+                .unwrap();
+        }
+
+        fn temp_path(name: &str) -> PathBuf {
+            std::env::temp_dir().join(format!("rusty_pipe_{}_{}", std::process::id(), name))
+        }
+
+        fn u32_at(data: &[u8], pos: usize) -> u32 {
+            u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+        }
+
+        fn u16_at(data: &[u8], pos: usize) -> u16 {
+            u16::from_le_bytes([data[pos], data[pos + 1]])
+        }
+
+        fn samples(data: &[u8]) -> Vec<f32> {
+            data.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        }
+
+        #[test]
+        fn wav_holds_the_samples_written() {
+            let path = temp_path("single.wav");
+            let stereo = spec(48000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+            {
+                let mut output =
+                    SinkOutput::try_open(&Sink::Wav(path.clone()), stereo, 256).unwrap();
+                write(output.as_mut(), stereo, 100, 0.5);
+            }
+            let data = std::fs::read(&path).unwrap();
+            assert_eq!(&data[0..4], b"RIFF");
+            assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+            assert_eq!(u16_at(&data, 22), 2);
+            assert_eq!(u32_at(&data, 24), 48000);
+            assert_eq!(u32_at(&data, 40), 100 * 2 * 4);
+            assert!(samples(&data[44..]).iter().all(|s| *s == 0.5));
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn reopened_wav_appends_in_its_format() {
+            let path = temp_path("reopened.wav");
+            let stereo = spec(48000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+            let mono = spec(48000, Channels::FRONT_LEFT);
+            let sink = Sink::Wav(path.clone());
+            let mut first = SinkOutput::try_open(&sink, stereo, 256).unwrap();
+            write(first.as_mut(), stereo, 100, 0.5);
+            // The next output is opened before the previous one is dropped, as the player does.
+            let mut second = SinkOutput::try_open(&sink, mono, 256).unwrap();
+            drop(first);
+            write(second.as_mut(), mono, 50, 0.25);
+            drop(second);
+
+            let data = std::fs::read(&path).unwrap();
+            assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+            assert_eq!(u16_at(&data, 22), 2);
+            assert_eq!(u32_at(&data, 40) as usize, data.len() - 44);
+            let samples = samples(&data[44..]);
+            assert_eq!(samples.len(), (100 + 50) * 2);
+            assert!(samples[..200].iter().all(|s| *s == 0.5));
+            // The mono track plays on both channels.
+            assert!(samples[200..].iter().all(|s| *s == 0.25));
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn reopened_wav_resamples_to_its_rate() {
+            let path = temp_path("resampled.wav");
+            let channels = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+            let sink = Sink::Wav(path.clone());
+            {
+                let mut output = SinkOutput::try_open(&sink, spec(48000, channels), 4096).unwrap();
+                write(output.as_mut(), spec(48000, channels), 4800, 0.5);
+            }
+            {
+                let mut output = SinkOutput::try_open(&sink, spec(44100, channels), 4096).unwrap();
+                write(output.as_mut(), spec(44100, channels), 4096, 0.5);
+            }
+            let data = std::fs::read(&path).unwrap();
+            assert_eq!(u32_at(&data, 24), 48000);
+            assert_eq!(u32_at(&data, 40) as usize, data.len() - 44);
+            // Whole frames only, and no more than the 44.1 kHz samples converted to 48 kHz.
+            let frames = (data.len() - 44) / 8;
+            assert_eq!((data.len() - 44) % 8, 0);
+            assert!(frames > 4800 && frames <= 4800 + 4096 * 48000 / 44100 + 1);
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn null_sink_advances_the_clock() {
+            let mono = spec(8000, Channels::FRONT_LEFT);
+            let mut output = SinkOutput::try_open(&Sink::Null, mono, 8000).unwrap();
+            write(output.as_mut(), mono, 4000, 0.1);
+            write(output.as_mut(), mono, 2000, 0.1);
+            assert!((output.played_secs() - 0.75).abs() < 1e-9);
+        }
+    }
+}
+
+/// Opens an output on `sink`. Device outputs play on the device called `device`, or on the
+/// default device.
+pub fn try_open(
+    sink: &Sink,
+    spec: SignalSpec,
+    duration: Duration,
+    device: Option<&str>,
+) -> Result<Box<dyn AudioOutput>> {
+    match sink {
+        Sink::Device => cpal::CpalAudioOutput::try_open(spec, duration, device),
+        _ => sink::SinkOutput::try_open(sink, spec, duration),
+    }
+}
+
+/// Names of the audio output devices.
+pub fn output_devices() -> Vec<String> {
+    cpal::device_names()
 }
 
-pub fn try_open(spec: SignalSpec, duration: Duration) -> Result<Box<dyn AudioOutput>> {
-    cpal::CpalAudioOutput::try_open(spec, duration)
+pub fn default_output_device() -> Option<String> {
+    cpal::default_device_name()
 }
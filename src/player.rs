@@ -324,6 +324,10 @@ pub fn play_track(
         .n_frames
         .map(|frames| track.codec_params.start_ts + frames);
 
+    // Spec and capacity the output was opened for. An output carried over from before a reset is
+    // opened again, the new track may have another spec.
+    let mut opened_for = None;
+
     // Decode and play the packets belonging to the selected track.
     let result = loop {
         // Get the next packet from the format reader.
@@ -349,21 +353,32 @@ pub fn play_track(
         // Decode the packet into audio samples.
         match decoder.decode(&packet) {
             Ok(decoded) => {
-                // If the audio output is not open, try to open it.
-                if audio_output.is_none() {
-                    // Get the audio buffer specification. This is a description of the decoded
-                    // audio buffer's sample format and sample rate.
-                    let spec = *decoded.spec();
-
-                    // Get the capacity of the decoded buffer. Note that this is capacity, not
-                    // length! The capacity of the decoded buffer is constant for the life of the
-                    // decoder, but the length is not.
-                    let duration = decoded.capacity() as u64;
-
-                    // Try to open the audio output.
-                    audio_output.replace(super::output::try_open(spec, duration).unwrap());
-                } else {
-                    // TODO: Check the audio spec. and duration hasn't changed.
+                // Get the audio buffer specification. This is a description of the decoded
+                // audio buffer's sample format and sample rate.
+                let spec = *decoded.spec();
+
+                // Get the capacity of the decoded buffer. Note that this is capacity, not
+                // length! The capacity of the decoded buffer is constant for the life of the
+                // decoder, but the length is not.
+                let duration = decoded.capacity() as u64;
+
+                // If the audio output is not open here yet, or was opened for another spec or for
+                // shorter buffers, try to open it.
+                let fits = match opened_for {
+                    Some((opened_spec, opened_duration)) => {
+                        opened_spec == spec && opened_duration >= duration
+                    }
+                    None => false,
+                };
+                if !fits {
+                    // Close the previous output first so that it releases the device.
+                    *audio_output = None;
+                    let sink = &super::output::Sink::Device;
+                    match super::output::try_open(sink, spec, duration, None) {
+                        Ok(output) => audio_output.replace(output),
+                        Err(err) => break Err(output_error(err)),
+                    };
+                    opened_for = Some((spec, duration));
                 }
 
                 // Write the decoded audio samples to the audio output if the presentation timestamp
@@ -409,7 +424,9 @@ pub fn play_track(
                     }
 
                     if let Some(audio_output) = audio_output {
-                        audio_output.write(decoded).unwrap()
+                        if let Err(err) = audio_output.write(decoded) {
+                            break Err(output_error(err));
+                        }
                     }
                 }
             }
@@ -436,6 +453,15 @@ pub fn play_track(
     result
 }
 
+/// Reports a failure of the audio output as an IO error, the only kind of error symphonia has
+/// for it.
+fn output_error(err: super::output::AudioOutputError) -> Error {
+    Error::IoError(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        format!("Audio output failed {:?}", err),
+    ))
+}
+
 fn first_supported_track(tracks: &[Track]) -> Option<&Track> {
     tracks
         .iter()
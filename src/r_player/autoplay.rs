@@ -0,0 +1,102 @@
+use std::collections::VecDeque;
+
+use rusty_pipe::youtube_extractor::{
+    search_extractor::YTSearchItem, stream_extractor::YTStreamExtractor,
+};
+
+use crate::{
+    cache,
+    downloader::refresh,
+    server::schema::{AutoplayOptions, PlayOptions},
+    yt_downloader::YTDownloader,
+};
+
+/// Radio mode: keeps playback going once the queue runs dry by picking a related video of the
+/// last played one.
+#[derive(Default)]
+pub struct Autoplay {
+    options: AutoplayOptions,
+    /// Recently played video ids, most recent last, never picked again while in the window.
+    history: VecDeque<String>,
+}
+
+impl Autoplay {
+    pub fn enabled(&self) -> bool {
+        self.options.enabled
+    }
+
+    pub fn configure(&mut self, options: AutoplayOptions) {
+        self.options = options;
+        self.trim_history();
+    }
+
+    /// Remembers `video_id` as played.
+    pub fn record(&mut self, video_id: &str) {
+        if self.history.back().map(|id| id.as_str()) == Some(video_id) {
+            return;
+        }
+        self.history.retain(|id| id != video_id);
+        self.history.push_back(video_id.to_string());
+        self.trim_history();
+    }
+
+    fn trim_history(&mut self) {
+        while self.history.len() > self.options.history_size {
+            self.history.pop_front();
+        }
+    }
+
+    /// Resolves the first related video of `video_id` that was not played recently and fits the
+    /// duration limit, in its lowest bitrate if `metered`. Fails if the related videos cant be
+    /// extracted.
+    pub async fn next_track(
+        &self,
+        video_id: &str,
+        metered: bool,
+    ) -> Result<Option<PlayOptions>, String> {
+        let extractor = YTStreamExtractor::new(video_id, YTDownloader {})
+            .await
+            .map_err(|err| format!("Cant get related videos for autoplay {:?}", err))?;
+        let related = extractor
+            .get_related()
+            .map_err(|err| format!("Cant get related videos for autoplay {:?}", err))?;
+        for item in related {
+            let video = match item {
+                YTSearchItem::StreamInfoItem(video) => video,
+                _ => continue,
+            };
+            let related_id = match video.video_id() {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            if related_id == video_id || self.history.contains(&related_id) {
+                continue;
+            }
+            if video.is_live().unwrap_or(false) || video.is_ad().unwrap_or(false) {
+                continue;
+            }
+            if let Some(max_duration) = self.options.max_duration {
+                match video.get_duration() {
+                    Ok(duration) if duration >= 0 && duration as u64 <= max_duration => {}
+                    _ => continue,
+                }
+            }
+            match refresh::resolve_stream(&related_id, metered).await {
+                Ok((url, length)) => {
+                    log::info!("Autoplay picked {}", related_id);
+                    return Ok(Some(PlayOptions {
+                        file_path: cache::cache_path(&related_id),
+                        video_id: related_id,
+                        title: video.get_name().ok(),
+                        url,
+                        length,
+                    }));
+                }
+                Err(err) => {
+                    log::warn!("Cant resolve autoplay video {} {:#?}", related_id, err);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
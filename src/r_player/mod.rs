@@ -1,35 +1,67 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
 
 use async_std::{future, prelude::*};
 use futures::{
-    channel::mpsc::{Receiver, Sender},
+    channel::mpsc::{Receiver, Sender, UnboundedSender},
     SinkExt, StreamExt,
 };
 use rusty_pipe::youtube_extractor::stream_extractor::YTStreamExtractor;
 use symphonia::core::{
+    audio::{AudioBuffer, AudioBufferRef, SignalSpec},
     codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error,
     formats::{FormatReader, Packet, SeekMode, SeekTo, Track},
     units::Time,
 };
 
 use crate::{
-    downloader::DownloaderS,
-    output::AudioOutput,
+    cache::CacheManager,
+    config::Config,
+    downloader::{DownloadError, DownloadEvent, DownloadOptions, DownloaderInput, DownloaderS},
+    dsp::{
+        channels::{ChannelMixer, ChannelOptions},
+        crossfade::SampleQueue,
+        equalizer::{
+            builtin_presets, Equalizer, EqualizerOptions, EqualizerPreset, BAND_FREQUENCIES,
+        },
+        loudness::{Limiter, LoudnessInfo, NormalizationOptions, Normalizer},
+        resample::ResampleQuality,
+        stretch::{MAX_SPEED, MIN_SPEED},
+        trim_start, AudioProcessor,
+    },
+    output::{AudioOutput, AudioOutputError, Sink},
     player::{print_progress, print_update, PlayTrackOptions},
-    server::schema::{PlayerMessage, PlayerStatus, ToPlayerMessages},
+    server::schema::{
+        BufferingStatus, EqualizerBand, EqualizerStatus, PlayOptions, PlayerError, PlayerErrorKind,
+        PlayerMessage, PlayerState, PlayerStatus, SeekStatus, StateStatus, ToPlayerMessages,
+        TrackInfo,
+    },
     yt_downloader::YTDownloader,
     StreamResponse,
 };
 
+use self::{autoplay::Autoplay, queue::PlayQueue};
+
+mod autoplay;
+mod queue;
+
+/// Runs the player, sending its audio to `sink`, or to the sink from the config when not given.
 pub async fn run_audio_player(
     mut msg_receiver: Receiver<ToPlayerMessages>,
     msg_sender: Sender<PlayerMessage>,
+    sink: Option<Sink>,
 ) {
     let pending_player_messages = Arc::new(Mutex::new(vec![]));
     let pending_messages_c = pending_player_messages.clone();
 
     let player_fut = async_std::task::spawn_blocking(move || {
-        async_std::task::block_on(async { play_audio(pending_player_messages, msg_sender).await })
+        async_std::task::block_on(async {
+            play_audio(pending_player_messages, msg_sender, sink).await
+        })
     });
 
     let messages_fut = async {
@@ -45,22 +77,105 @@ pub async fn run_audio_player(
 
 async fn play_audio(
     messages: Arc<Mutex<Vec<ToPlayerMessages>>>,
-    mut msg_sender: Sender<PlayerMessage>,
+    msg_sender: Sender<PlayerMessage>,
+    sink: Option<Sink>,
 ) {
     let (txdsend, rxdsend) = crossbeam_channel::unbounded();
-    let (txdrecv, rxdrecv) = crossbeam_channel::unbounded();
-    let mut down = DownloaderS::new(rxdsend, txdrecv);
+    // Every stream subscribes to its own replies, nothing is expected here.
+    let (txdrecv, _rxdrecv) = crossbeam_channel::unbounded();
+    let mut down = DownloaderS::new(rxdsend, txdrecv).with_options(Config::load().download);
 
     let down_loader_task = async move {
         down.run().await;
     };
 
+    let (event_sender, mut event_receiver) = futures::channel::mpsc::unbounded();
+    if let Err(err) = txdsend.send(DownloaderInput::Watch(event_sender.clone())) {
+        log::warn!("Cant watch downloads {:#?}", err);
+    }
+    let streams = Streams {
+        downloader: txdsend,
+        events: event_sender,
+    };
+    let mut clients = ClientEvents {
+        sender: msg_sender,
+        last_state: Arc::new(Mutex::new(None)),
+    };
+
+    // Downloads report from the downloader and from streams blocked in the player loop, so they
+    // are forwarded to clients from here.
+    let mut events_sender = clients.sender.clone();
+    let last_state = clients.last_state.clone();
+    let events_task = async move {
+        while let Some(event) = event_receiver.next().await {
+            let state = last_state.lock().ok().and_then(|s| s.clone());
+            let current = state.as_ref().and_then(|s| s.video_id.clone());
+            let msg = match event {
+                DownloadEvent::Progress {
+                    video_id,
+                    percent,
+                    bytes_per_sec,
+                    buffered_secs,
+                } => PlayerMessage::Buffering(BufferingStatus {
+                    video_id,
+                    percent,
+                    bytes_per_sec,
+                    buffered_secs,
+                }),
+                DownloadEvent::Stalled(video_id) if current.as_ref() == Some(&video_id) => {
+                    PlayerMessage::State(StateStatus {
+                        state: PlayerState::Buffering,
+                        video_id: Some(video_id),
+                    })
+                }
+                DownloadEvent::Resumed(video_id) if current.as_ref() == Some(&video_id) => {
+                    match state {
+                        Some(state) => PlayerMessage::State(state),
+                        None => continue,
+                    }
+                }
+                DownloadEvent::Failed { video_id, message } => PlayerMessage::Error(PlayerError {
+                    kind: PlayerErrorKind::Network,
+                    message,
+                    video_id: Some(video_id),
+                }),
+                _ => continue,
+            };
+            if let Err(err) = events_sender.send(msg).await {
+                log::warn!("Cant send download event {:#?}", err);
+            }
+        }
+    };
+
     let player_task = async {
         log::info!("Player started");
         async_std::task::spawn_blocking(move || {
             async_std::task::block_on(async {
                 let mut playing_data: Option<PlayingData> = None;
+                let mut preload: Option<Preload> = None;
+                let mut queue = PlayQueue::default();
+                let mut autoplay = Autoplay::default();
+                let mut settings = PlayerSettings::default();
                 let mut last_sent = None;
+                let mut last_queue_sent = None;
+                let mut last_equalizer_sent = None;
+                let config = Config::load();
+                settings.output_device = config.output_device;
+                settings.resample_quality = config.resample_quality;
+                settings.download = config.download;
+                settings.sink = sink
+                    .or_else(|| {
+                        config.sink.as_ref().and_then(|sink| match sink.parse() {
+                            Ok(sink) => Some(sink),
+                            Err(err) => {
+                                log::warn!("{}", err);
+                                None
+                            }
+                        })
+                    })
+                    .unwrap_or_default();
+                let mut equalizer_presets = builtin_presets();
+                equalizer_presets.extend(config.equalizer_presets);
 
                 loop {
                     // log::info!("Get play msg");
@@ -88,140 +203,377 @@ async fn play_audio(
                             ToPlayerMessages::Play(options) => {
                                 if let Some(playing_data) = &mut playing_data {
                                     if &playing_data.url == &options.url {
-                                        playing_data.is_playing = true;
+                                        playing_data.resume();
                                         continue;
                                     }
                                 }
-                                let new_playing_data = create_new_player(
-                                    &options.url,
-                                    &rxdrecv,
-                                    &txdsend,
-                                    options.length,
-                                    options.file_path,
-                                    options.video_id,
+                                let new_playing_data = start_track(
+                                    queue.play_now(options),
+                                    &streams,
+                                    &mut clients,
                                     playing_data.and_then(|d| d.audio_output),
-                                );
+                                )
+                                .await;
 
                                 // Decode the packet into audio samples.
                                 playing_data = new_playing_data;
                             }
+                            ToPlayerMessages::Enqueue(options) => {
+                                queue.enqueue(options);
+                                if playing_data.is_none() {
+                                    playing_data =
+                                        start_track(queue.next(), &streams, &mut clients, None)
+                                            .await;
+                                }
+                            }
+                            ToPlayerMessages::PlayNext(options) => {
+                                queue.insert_next(options);
+                                if playing_data.is_none() {
+                                    playing_data =
+                                        start_track(queue.next(), &streams, &mut clients, None)
+                                            .await;
+                                }
+                            }
+                            ToPlayerMessages::RemoveFromQueue(index) => {
+                                if queue.remove(index) {
+                                    playing_data = start_track(
+                                        queue.current(),
+                                        &streams,
+                                        &mut clients,
+                                        playing_data.and_then(|d| d.audio_output),
+                                    )
+                                    .await;
+                                }
+                            }
+                            ToPlayerMessages::MoveInQueue(from, to) => {
+                                queue.move_item(from, to);
+                            }
+                            ToPlayerMessages::ClearQueue => {
+                                queue.clear();
+                            }
+                            ToPlayerMessages::Next => {
+                                if let Some(options) = queue.next().cloned() {
+                                    playing_data = switch_track(
+                                        &options,
+                                        &mut preload,
+                                        &streams,
+                                        &mut clients,
+                                        playing_data.and_then(|d| d.audio_output),
+                                    )
+                                    .await;
+                                }
+                            }
+                            ToPlayerMessages::SetShuffle(shuffle, seed) => {
+                                queue.set_shuffle(shuffle, seed);
+                            }
+                            ToPlayerMessages::SetRepeat(repeat) => {
+                                queue.set_repeat(repeat);
+                            }
+                            ToPlayerMessages::SetAutoplay(options) => {
+                                autoplay.configure(options);
+                            }
+                            ToPlayerMessages::SetVolume(volume) => {
+                                settings.volume = volume.min(MAX_VOLUME_PERCENT);
+                                if let Some(output) = playing_data
+                                    .as_mut()
+                                    .and_then(|pd| pd.audio_output.as_mut())
+                                {
+                                    settings.apply(output.output.as_mut());
+                                }
+                            }
+                            ToPlayerMessages::SetNormalization(options) => {
+                                settings.normalization = options;
+                            }
+                            ToPlayerMessages::SetSpeed(speed) => {
+                                settings.speed = speed.max(MIN_SPEED as f64).min(MAX_SPEED as f64);
+                                if let Some(output) = playing_data
+                                    .as_mut()
+                                    .and_then(|pd| pd.audio_output.as_mut())
+                                {
+                                    settings.apply(output.output.as_mut());
+                                }
+                            }
+                            ToPlayerMessages::EnableEqualizer(enabled) => {
+                                settings.equalizer.enabled = enabled;
+                            }
+                            ToPlayerMessages::SetEqualizer(mut options) => {
+                                options.sanitize();
+                                settings.equalizer = options;
+                            }
+                            ToPlayerMessages::SetEqualizerPreset(name) => {
+                                match equalizer_presets.iter().find(|p| p.name == name) {
+                                    Some(preset) => {
+                                        settings.equalizer =
+                                            EqualizerOptions::from_preset(true, preset);
+                                    }
+                                    None => log::warn!("No equalizer preset named {}", name),
+                                }
+                            }
+                            ToPlayerMessages::SetEqualizerBand(band, gain) => {
+                                settings.equalizer.set_band(band, gain);
+                            }
+                            ToPlayerMessages::SetMono(mono) => {
+                                settings.channels.mono = mono;
+                            }
+                            ToPlayerMessages::SetBalance(balance) => {
+                                settings.channels.balance = balance.max(-1.0).min(1.0);
+                            }
+                            ToPlayerMessages::SetBandwidthLimit(total, per_track) => {
+                                settings.download.max_bytes_per_sec = total;
+                                settings.download.max_track_bytes_per_sec = per_track;
+                                streams.set_download_options(settings.download);
+                            }
+                            ToPlayerMessages::SetMetered(metered) => {
+                                settings.download.metered = metered;
+                                streams.set_download_options(settings.download);
+                            }
+                            ToPlayerMessages::SetOutputDevice(name) => {
+                                settings.output_device = name;
+                                if let Some(pd) = &mut playing_data {
+                                    pd.reopen_output();
+                                }
+                            }
+                            ToPlayerMessages::SetCrossfade(secs) => {
+                                settings.crossfade_secs = secs.max(0.0);
+                            }
+                            ToPlayerMessages::Mute(muted) => {
+                                settings.muted = muted;
+                                if let Some(output) = playing_data
+                                    .as_mut()
+                                    .and_then(|pd| pd.audio_output.as_mut())
+                                {
+                                    settings.apply(output.output.as_mut());
+                                }
+                            }
+                            ToPlayerMessages::Previous => {
+                                playing_data = start_track(
+                                    queue.previous(),
+                                    &streams,
+                                    &mut clients,
+                                    playing_data.and_then(|d| d.audio_output),
+                                )
+                                .await;
+                            }
                             ToPlayerMessages::Pause => {
                                 if let Some(playing_data) = &mut playing_data {
                                     playing_data.is_playing = false;
                                 }
                             }
                             ToPlayerMessages::Seek(secs) => {
-                                if let Some(playing_data) = &mut playing_data {
-                                    if let Some(tb) = &playing_data.tb {
-                                        if let Some(packet) = &playing_data.last_packet {
-                                            let t = tb.calc_time(packet.pts()).seconds;
-                                            let nt = Time::new((t as i64 + secs) as u64, 0.0);
-                                            playing_data.reader.seek(
-                                                SeekMode::Accurate,
-                                                SeekTo::Time {
-                                                    time: nt,
-                                                    track_id: None,
-                                                },
-                                            );
-                                        }
-                                    }
-                                }
+                                let target = playing_data
+                                    .as_ref()
+                                    .map(|pd| pd.position_secs() + secs as f64);
+                                let status = seek_playing(&mut playing_data, target);
+                                clients.send(PlayerMessage::Seek(status)).await;
+                            }
+                            ToPlayerMessages::SeekTo(secs) => {
+                                let status = seek_playing(&mut playing_data, Some(secs));
+                                clients.send(PlayerMessage::Seek(status)).await;
+                            }
+                            ToPlayerMessages::SeekPercent(percent) => {
+                                let target = playing_data
+                                    .as_ref()
+                                    .and_then(|pd| pd.track.duration_secs())
+                                    .map(|dur| dur * percent.max(0.0).min(100.0) / 100.0);
+                                let status = seek_playing(&mut playing_data, target);
+                                clients.send(PlayerMessage::Seek(status)).await;
                             }
                             ToPlayerMessages::Resume => {
                                 if let Some(pd) = &mut playing_data {
-                                    pd.is_playing = true;
+                                    pd.resume();
                                 }
                             }
                         }
                     }
 
+                    let queue_status = queue.status();
+                    if last_queue_sent.as_ref() != Some(&queue_status) {
+                        clients
+                            .send(PlayerMessage::Queue(queue_status.clone()))
+                            .await;
+                        last_queue_sent = Some(queue_status);
+                    }
+
+                    let equalizer_status = settings.equalizer_status(&equalizer_presets);
+                    if last_equalizer_sent.as_ref() != Some(&equalizer_status) {
+                        clients
+                            .send(PlayerMessage::Equalizer(equalizer_status.clone()))
+                            .await;
+                        last_equalizer_sent = Some(equalizer_status);
+                    }
+
+                    if let Some(current) = queue.current() {
+                        autoplay.record(&current.video_id);
+                    }
+
+                    // Keep the track that follows the current one loaded ahead of time, so that it
+                    // can take over without a gap.
+                    let upcoming = queue.peek_advance().cloned();
+                    if let Some(p) = &preload {
+                        if upcoming.as_ref().map(|u| &u.url) != Some(&p.options.url) {
+                            preload = None;
+                        }
+                    }
+                    if let (Some(pd), Some(upcoming)) = (&playing_data, upcoming) {
+                        let near_end = pd
+                            .track
+                            .remaining_secs()
+                            .map(|r| r <= PRELOAD_SECS + settings.crossfade_secs)
+                            .unwrap_or(false);
+                        // The downloader keys streams by video, the same video cant be loaded
+                        // twice.
+                        if preload.is_none() && near_end && upcoming.video_id != pd.video_id {
+                            preload = Some(Preload::start(upcoming, &streams, &settings));
+                        }
+                    }
+                    if let Some(p) = &mut preload {
+                        p.poll();
+                    }
+
+                    let mut track_ended = false;
+                    // The track stopped on an error, rather than at its end.
+                    let mut track_failed = false;
                     if let Some(playing_data) = &mut playing_data {
                         if playing_data.is_playing {
                             // log::info!("playing data is play");
                             log::debug!("Trying to play");
-                            playing_data.play();
+                            let next = preload.as_mut().and_then(|p| p.playing_data.as_mut());
+                            match playing_data.play(&settings, next) {
+                                Ok(()) => {}
+                                Err(PlayError::Decode(Error::IoError(err)))
+                                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                                {
+                                    log::info!("Track ended");
+                                    playing_data.save_loudness();
+                                    track_ended = true;
+                                }
+                                Err(PlayError::Decode(Error::IoError(err)))
+                                    if err
+                                        .get_ref()
+                                        .map_or(false, |err| err.is::<DownloadError>()) =>
+                                {
+                                    // Already reported to clients by the download events.
+                                    log::warn!("Download failed, skipping the track {}", err);
+                                    track_ended = true;
+                                    track_failed = true;
+                                }
+                                Err(PlayError::Decode(err)) => {
+                                    let video_id = Some(playing_data.video_id.clone());
+                                    clients
+                                        .error(PlayerErrorKind::Decode, err.to_string(), video_id)
+                                        .await;
+                                    track_ended = true;
+                                    track_failed = true;
+                                }
+                                Err(PlayError::Output(AudioOutputError::DeviceNotAvailable)) => {
+                                    let video_id = Some(playing_data.video_id.clone());
+                                    clients
+                                        .error(
+                                            PlayerErrorKind::Output,
+                                            "Audio device went away, switching to the default one"
+                                                .to_string(),
+                                            video_id,
+                                        )
+                                        .await;
+                                    playing_data.reopen_output();
+                                }
+                                Err(PlayError::Output(err)) => {
+                                    // Pause until the user resumes, which reopens the output.
+                                    let video_id = Some(playing_data.video_id.clone());
+                                    clients
+                                        .error(
+                                            PlayerErrorKind::Output,
+                                            format!("{:?}", err),
+                                            video_id,
+                                        )
+                                        .await;
+                                    playing_data.audio_output = None;
+                                    playing_data.is_playing = false;
+                                }
+                            }
                             log::debug!("Play done");
-                            let to_send = PlayerMessage::Status(PlayerStatus {
-                                playing: true,
-                                current_status: {
-                                    if let Some(tb) = playing_data.tb {
-                                        if let Some(packet) = &playing_data.last_packet {
-                                            let t = tb.calc_time(packet.pts()).seconds;
-                                            Some(t)
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                },
-                                total_time: {
-                                    if let Some(tb) = playing_data.tb {
-                                        if let Some(packet) = &playing_data.last_packet {
-                                            if let Some(dur) = playing_data.dur {
-                                                let d = tb.calc_time(dur);
-                                                Some(d.seconds)
-                                            } else {
-                                                None
-                                            }
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                },
-                            });
+                            let to_send = PlayerMessage::Status(
+                                playing_data.status(true, &queue, &autoplay, &settings),
+                            );
                             // log::info!("playing frame ended");
 
                             if last_sent != Some(to_send.clone()) {
-                                let err = msg_sender.send(to_send.clone()).await;
+                                clients.send(to_send.clone()).await;
                                 last_sent = Some(to_send);
                             }
                         } else {
-                            let to_send = PlayerMessage::Status(PlayerStatus {
-                                playing: false,
-                                current_status: {
-                                    if let Some(tb) = playing_data.tb {
-                                        if let Some(packet) = &playing_data.last_packet {
-                                            let t = tb.calc_time(packet.pts()).seconds;
-                                            Some(t)
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                },
-                                total_time: {
-                                    if let Some(tb) = playing_data.tb {
-                                        if let Some(packet) = &playing_data.last_packet {
-                                            if let Some(dur) = playing_data.dur {
-                                                let d = tb.calc_time(dur);
-                                                Some(d.seconds)
-                                            } else {
-                                                None
-                                            }
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                },
-                            });
+                            let to_send = PlayerMessage::Status(
+                                playing_data.status(false, &queue, &autoplay, &settings),
+                            );
                             if last_sent != Some(to_send.clone()) {
-                                let err = msg_sender.send(to_send.clone()).await;
+                                clients.send(to_send.clone()).await;
                                 last_sent = Some(to_send);
                             }
                         }
                     }
+                    if track_ended {
+                        // Advance to the next queued track, keeping the output open and taking
+                        // over the preloaded track if there is one. When the queue is exhausted
+                        // let autoplay extend it, or stay on the finished track, paused.
+                        // A track that failed is not repeated, it would only fail again.
+                        let mut next = if track_failed {
+                            queue.skip_failed().cloned()
+                        } else {
+                            queue.advance().cloned()
+                        };
+                        if next.is_none() && !track_failed && autoplay.enabled() {
+                            let last_played = queue.current().map(|c| c.video_id.clone());
+                            if let Some(last_played) = last_played {
+                                let metered = settings.download.metered;
+                                match autoplay.next_track(&last_played, metered).await {
+                                    Ok(Some(options)) => {
+                                        queue.enqueue(options);
+                                        next = queue.next().cloned();
+                                    }
+                                    Ok(None) => {}
+                                    Err(message) => {
+                                        clients
+                                            .error(
+                                                PlayerErrorKind::Extractor,
+                                                message,
+                                                Some(last_played),
+                                            )
+                                            .await;
+                                    }
+                                }
+                            }
+                        }
+                        if let Some(next) = next {
+                            playing_data = switch_track(
+                                &next,
+                                &mut preload,
+                                &streams,
+                                &mut clients,
+                                playing_data.and_then(|d| d.audio_output),
+                            )
+                            .await;
+                        } else if let Some(pd) = &mut playing_data {
+                            pd.end_output();
+                            pd.is_playing = false;
+                            pd.ended = true;
+                        }
+                    }
+
+                    let state = match &playing_data {
+                        Some(pd) if pd.ended => PlayerState::Ended,
+                        Some(pd) if pd.is_playing => PlayerState::Playing,
+                        Some(_) => PlayerState::Paused,
+                        None => PlayerState::Idle,
+                    };
+                    clients
+                        .state(state, playing_data.as_ref().map(|pd| pd.video_id.clone()))
+                        .await;
                     match &playing_data {
                         Some(pd) => {
                             if !pd.is_playing {
                                 async_std::task::sleep(std::time::Duration::from_millis(50)).await;
-                            } else if let Some(packe) = &pd.last_packet {
-                                if packe.pts() >= pd.dur.unwrap_or(std::u64::MAX) {
+                            } else if let Some(packe) = &pd.track.last_packet {
+                                if packe.pts() >= pd.track.dur.unwrap_or(std::u64::MAX) {
                                     async_std::task::sleep(std::time::Duration::from_millis(50))
                                         .await;
                                 }
@@ -237,30 +589,177 @@ async fn play_audio(
         .await
     };
 
-    futures::join!(player_task, down_loader_task);
+    futures::join!(player_task, down_loader_task, events_task);
 }
 
-fn create_new_player(
-    url: &String,
-    rxdrecv: &crossbeam_channel::Receiver<crate::downloader::Reply>,
-    txdsend: &crossbeam_channel::Sender<crate::downloader::DownloaderInput>,
-    length: Option<usize>,
-    file_path: Option<String>,
-    video_id: String,
-    audio_output: Option<Box<dyn AudioOutput>>,
+/// Sends player messages to clients and keeps track of the state last reported.
+struct ClientEvents {
+    sender: Sender<PlayerMessage>,
+    /// Shared with the download events, which report buffering on top of this state.
+    last_state: Arc<Mutex<Option<StateStatus>>>,
+}
+
+impl ClientEvents {
+    async fn send(&mut self, msg: PlayerMessage) {
+        if let Err(err) = self.sender.send(msg).await {
+            log::warn!("Cant send player message {:#?}", err);
+        }
+    }
+
+    /// Reports the player state if it changed since it was last reported.
+    async fn state(&mut self, state: PlayerState, video_id: Option<String>) {
+        let status = StateStatus { state, video_id };
+        match self.last_state.lock() {
+            Ok(mut last) => {
+                if last.as_ref() == Some(&status) {
+                    return;
+                }
+                *last = Some(status.clone());
+            }
+            Err(err) => log::warn!("{:#?}", err),
+        }
+        self.send(PlayerMessage::State(status)).await;
+    }
+
+    async fn error(&mut self, kind: PlayerErrorKind, message: String, video_id: Option<String>) {
+        log::warn!("{:?} error {}", kind, message);
+        self.send(PlayerMessage::Error(PlayerError {
+            kind,
+            message,
+            video_id,
+        }))
+        .await;
+    }
+}
+
+/// Opens streams through the downloader, with their progress reported to the player.
+struct Streams {
+    downloader: crossbeam_channel::Sender<DownloaderInput>,
+    events: UnboundedSender<DownloadEvent>,
+}
+
+impl Streams {
+    fn open(&self, options: &PlayOptions) -> StreamResponse {
+        StreamResponse::new(
+            options.url.clone(),
+            options.video_id.clone(),
+            options.file_path.clone(),
+            options.length,
+            self.downloader.clone(),
+        )
+        .with_events(self.events.clone())
+    }
+
+    /// Tells the downloader how many bytes a second of `playing_data` takes, so that it reads
+    /// ahead by time rather than by bytes.
+    fn report_byte_rate(&self, options: &PlayOptions, playing_data: &PlayingData) {
+        let rate = match (options.length, playing_data.track.duration_secs()) {
+            (Some(length), Some(secs)) if secs > 0.0 => length as f64 / secs,
+            _ => return,
+        };
+        let input = DownloaderInput::ByteRate(options.video_id.clone(), rate);
+        if let Err(err) = self.downloader.send(input) {
+            log::warn!("Cant send byte rate to downloader {:#?}", err);
+        }
+    }
+
+    fn set_download_options(&self, options: DownloadOptions) {
+        if let Err(err) = self.downloader.send(DownloaderInput::SetOptions(options)) {
+            log::warn!("Cant send download options to downloader {:#?}", err);
+        }
+    }
+}
+
+/// Opens `options` and reports the loading, the new track or the failure to clients.
+async fn start_track(
+    options: Option<&PlayOptions>,
+    streams: &Streams,
+    clients: &mut ClientEvents,
+    audio_output: Option<OpenOutput>,
+) -> Option<PlayingData> {
+    let options = options?;
+    clients
+        .state(PlayerState::Loading, Some(options.video_id.clone()))
+        .await;
+    match create_new_player(options, streams, audio_output) {
+        Ok(playing_data) => {
+            streams.report_byte_rate(options, &playing_data);
+            mark_played(options);
+            clients
+                .send(PlayerMessage::TrackChanged(playing_data.track_info()))
+                .await;
+            Some(playing_data)
+        }
+        Err(message) => {
+            clients
+                .error(
+                    PlayerErrorKind::Decode,
+                    message,
+                    Some(options.video_id.clone()),
+                )
+                .await;
+            None
+        }
+    }
+}
+
+/// Starts playing `options`, taking over the preloaded track if it is the same one so that
+/// playback continues without a gap.
+async fn switch_track(
+    options: &PlayOptions,
+    preload: &mut Option<Preload>,
+    streams: &Streams,
+    clients: &mut ClientEvents,
+    audio_output: Option<OpenOutput>,
 ) -> Option<PlayingData> {
+    let preloaded = preload
+        .take()
+        .filter(|p| p.options.url == options.url)
+        .and_then(|p| p.playing_data);
+    match preloaded {
+        Some(mut playing_data) => {
+            log::info!("Continuing into preloaded track");
+            playing_data.audio_output = audio_output;
+            playing_data.is_playing = true;
+            streams.report_byte_rate(options, &playing_data);
+            mark_played(options);
+            clients
+                .send(PlayerMessage::TrackChanged(playing_data.track_info()))
+                .await;
+            Some(playing_data)
+        }
+        None => start_track(Some(options), streams, clients, audio_output).await,
+    }
+}
+
+/// Records in the cache that the track of `options` is played, so that it is evicted last.
+fn mark_played(options: &PlayOptions) {
+    if options.file_path.is_some() {
+        if let Some(cache) = CacheManager::open() {
+            cache.touch(&options.video_id);
+        }
+    }
+}
+
+fn create_new_player(
+    options: &PlayOptions,
+    streams: &Streams,
+    audio_output: Option<OpenOutput>,
+) -> Result<PlayingData, String> {
     log::info!("Decoding stream");
-    let decoded_data = crate::decode_m4a::decode(StreamResponse {
-        url: url.clone(),
-        current_position: 0,
-        down_rcv: rxdrecv.clone(),
-        down_sender: txdsend.clone(),
-        total_length: length,
-        video_id,
-        file_name: file_path,
-    });
+    let decoded_data = crate::decode_m4a::decode(streams.open(options))
+        .map_err(|err| format!("Cant open stream {}", err))?;
     log::info!("Decoded stream");
-    let mut reader = decoded_data;
+    let track = track_from_reader(decoded_data, options)?;
+    Ok(PlayingData::new(track, options, audio_output))
+}
+
+fn track_from_reader(
+    reader: Box<dyn FormatReader>,
+    options: &PlayOptions,
+) -> Result<TrackDecoder, String> {
+    let normalizer = Normalizer::new(options.file_path.as_deref().and_then(LoudnessInfo::load));
+    let mut reader = reader;
     let track_num: Option<usize> = None;
     let seek_time: Option<f64> = None;
     let decode_opts = &DecoderOptions { verify: false };
@@ -271,8 +770,7 @@ fn create_new_player(
     let mut track_id = match track {
         Some(track) => track.id,
         _ => {
-            log::warn!("No tracks found");
-            return None;
+            return Err("No tracks found".to_string());
         }
     };
     let seek_ts = if let Some(time) = seek_time {
@@ -285,7 +783,7 @@ fn create_new_player(
         // that no samples are trimmed.
         match reader.seek(SeekMode::Accurate, seek_to) {
             Ok(seeked_to) => seeked_to.required_ts,
-            Err(symphonia::core::errors::Error::ResetRequired) => {
+            Err(Error::ResetRequired) => {
                 // print_tracks(reader.tracks());
                 track_id = first_supported_track(reader.tracks()).unwrap().id;
                 0
@@ -309,14 +807,13 @@ fn create_new_player(
         .find(|track| track.id == play_opts.track_id)
     {
         Some(track) => track,
-        _ => return None,
+        _ => return Err("Track not found".to_string()),
     };
     let mut decoder = match symphonia::default::get_codecs().make(&track.codec_params, decode_opts)
     {
         Ok(val) => val,
         Err(err) => {
-            log::warn!("Decoder error {:#?}", err);
-            return None;
+            return Err(format!("Decoder error {}", err));
         }
     };
     let mut tb = track.codec_params.time_base;
@@ -326,41 +823,630 @@ fn create_new_player(
         .map(|frames| track.codec_params.start_ts + frames);
 
     log::info!("Player Created");
-    Some(PlayingData {
+    Ok(TrackDecoder {
         decoder,
         reader,
         // packet,
-        audio_output,
         play_opts,
         no_progress,
         dur,
         tb,
-        is_playing: true,
-        url: url.to_string(),
         last_packet: None,
+        decoded_end_secs: 0.0,
+        processed: None,
+        pending: None,
+        normalizer,
+        equalizer: Equalizer::default(),
+        channel_mixer: ChannelMixer::default(),
+        limiter: Limiter::default(),
     })
 }
 
-struct PlayingData {
+/// Seeks the playing track to `target` seconds and describes the outcome for clients. A missing
+/// target means it could not be worked out from the track.
+fn seek_playing(playing_data: &mut Option<PlayingData>, target: Option<f64>) -> SeekStatus {
+    let result = match (playing_data, target) {
+        (Some(pd), Some(target)) => pd.seek(target).map_err(|err| err.to_string()),
+        (None, _) => Err("Nothing is playing".to_string()),
+        (Some(_), None) => Err("Track length is unknown".to_string()),
+    };
+    match result {
+        Ok(position) => SeekStatus {
+            success: true,
+            position: Some(position),
+            error: None,
+        },
+        Err(error) => {
+            log::warn!("Seek failed {}", error);
+            SeekStatus {
+                success: false,
+                position: None,
+                error: Some(error),
+            }
+        }
+    }
+}
+
+/// How long before the end of the current track the next one starts loading, in seconds.
+const PRELOAD_SECS: f64 = 20.0;
+/// How much of the preloaded track is decoded ahead of time on top of the crossfade, in seconds.
+const PRE_DECODE_SECS: f64 = 1.0;
+
+/// The track following the current one, probed and decoded a little ahead on its own thread so
+/// it can take over, or be crossfaded into, without holding up the current track.
+struct Preload {
+    options: PlayOptions,
+    track: crossbeam_channel::Receiver<Result<TrackDecoder, String>>,
+    playing_data: Option<PlayingData>,
+}
+
+impl Preload {
+    /// Starts loading `options`, decoding as much of it as the crossfade of `settings` mixes in.
+    /// The processing stages use `settings` as they are now.
+    fn start(options: PlayOptions, streams: &Streams, settings: &PlayerSettings) -> Self {
+        log::info!("Preloading {}", options.video_id);
+        let (sender, track) = crossbeam_channel::bounded(1);
+        let stream = streams.open(&options);
+        let thread_options = options.clone();
+        let settings = settings.clone();
+        std::thread::spawn(move || {
+            let track = crate::decode_m4a::decode(stream)
+                .map_err(|err| format!("Cant open stream {}", err))
+                .and_then(|reader| track_from_reader(reader, &thread_options))
+                .map(|mut track| {
+                    let secs = settings.crossfade_secs + PRE_DECODE_SECS;
+                    // The error, such as the end of a short track, comes up again once the
+                    // track plays past what was decoded.
+                    if let Err(err) = track.decode_ahead(&settings, secs) {
+                        log::warn!("Cant decode preloaded track {:#?}", err);
+                    }
+                    track
+                });
+            if sender.send(track).is_err() {
+                log::debug!("Preload dropped before decoding finished");
+            }
+        });
+        Self {
+            options,
+            track,
+            playing_data: None,
+        }
+    }
+
+    /// Picks up the track once its start is decoded.
+    fn poll(&mut self) {
+        if self.playing_data.is_some() {
+            return;
+        }
+        match self.track.try_recv() {
+            Ok(Ok(track)) => {
+                self.playing_data = Some(PlayingData::new(track, &self.options, None));
+            }
+            Ok(Err(err)) => log::warn!("Cant preload {} {}", self.options.video_id, err),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Highest volume the player accepts, in percent.
+const MAX_VOLUME_PERCENT: u8 = 150;
+
+/// Settings that outlive a single track and are applied to every output the player opens.
+#[derive(Clone)]
+struct PlayerSettings {
+    /// Volume in percent, 100 being unity gain.
+    volume: u8,
+    muted: bool,
+    normalization: NormalizationOptions,
+    /// Length of the crossfade between consecutive tracks in seconds, 0 for none.
+    crossfade_secs: f64,
+    /// Playback rate, 1.0 being normal speed.
+    speed: f64,
+    equalizer: EqualizerOptions,
+    channels: ChannelOptions,
+    /// Name of the output device to open, the default device if none.
+    output_device: Option<String>,
+    sink: Sink,
+    resample_quality: ResampleQuality,
+    download: DownloadOptions,
+}
+
+impl Default for PlayerSettings {
+    fn default() -> Self {
+        Self {
+            volume: 100,
+            muted: false,
+            normalization: NormalizationOptions::default(),
+            crossfade_secs: 0.0,
+            speed: 1.0,
+            equalizer: EqualizerOptions::default(),
+            channels: ChannelOptions::default(),
+            output_device: None,
+            sink: Sink::default(),
+            resample_quality: ResampleQuality::default(),
+            download: DownloadOptions::default(),
+        }
+    }
+}
+
+impl PlayerSettings {
+    fn apply(&self, output: &mut dyn AudioOutput) {
+        output.set_volume(self.volume as f32 / 100.0);
+        output.set_mute(self.muted);
+        output.set_speed(self.speed as f32);
+        output.set_resample_quality(self.resample_quality);
+    }
+
+    fn equalizer_status(&self, presets: &[EqualizerPreset]) -> EqualizerStatus {
+        EqualizerStatus {
+            enabled: self.equalizer.enabled,
+            preset: self.equalizer.preset.clone(),
+            bands: BAND_FREQUENCIES
+                .iter()
+                .zip(self.equalizer.gains.iter())
+                .map(|(frequency, gain)| EqualizerBand {
+                    frequency: *frequency,
+                    gain: *gain,
+                })
+                .collect(),
+            presets: presets.iter().map(|p| p.name.clone()).collect(),
+        }
+    }
+}
+
+/// An open audio output with the format it was opened for. It is handed from track to track as
+/// long as their samples fit it.
+struct OpenOutput {
+    output: Box<dyn AudioOutput>,
+    spec: SignalSpec,
+    capacity: u64,
+}
+
+/// Reads, decodes and processes the packets of a track. It holds no output, so the next track can
+/// be decoded ahead on the preload thread.
+struct TrackDecoder {
     decoder: Box<dyn Decoder>,
     reader: Box<dyn FormatReader>,
     // packet: symphonia::core::formats::Packet,
-    audio_output: Option<Box<dyn AudioOutput>>,
     play_opts: PlayTrackOptions,
     no_progress: bool,
     dur: Option<u64>,
     tb: Option<symphonia::core::units::TimeBase>,
+    last_packet: Option<Packet>,
+    /// Position in the track at the end of the last decoded packet, in seconds.
+    decoded_end_secs: f64,
+    /// Decoded samples converted for the processing stages.
+    processed: Option<AudioBuffer<f32>>,
+    /// Processed samples decoded ahead of playback, played before anything new is decoded.
+    pending: Option<SampleQueue>,
+    normalizer: Normalizer,
+    equalizer: Equalizer,
+    channel_mixer: ChannelMixer,
+    limiter: Limiter,
+}
+
+struct PlayingData {
+    track: TrackDecoder,
+    audio_output: Option<OpenOutput>,
 
     is_playing: bool,
     url: String,
-    last_packet: Option<Packet>,
+    video_id: String,
+    title: Option<String>,
+    file_path: Option<String>,
+    /// Set once the track played to the end with nothing queued after it.
+    ended: bool,
+    /// Decoding started at the beginning of the track and never seeked, so the loudness measured
+    /// covers everything played.
+    unseeked: bool,
+    /// Output clock and track position at the end of every write not heard yet, in seconds.
+    timeline: VecDeque<(f64, f64)>,
 }
 impl PlayingData {
-    fn play(&mut self) -> Result<(), symphonia::core::errors::Error> {
+    fn new(track: TrackDecoder, options: &PlayOptions, audio_output: Option<OpenOutput>) -> Self {
+        Self {
+            unseeked: track.play_opts.seek_ts == 0,
+            track,
+            audio_output,
+            is_playing: true,
+            url: options.url.clone(),
+            video_id: options.video_id.clone(),
+            title: options.title.clone(),
+            file_path: options.file_path.clone(),
+            ended: false,
+            timeline: VecDeque::new(),
+        }
+    }
+
+    fn status(
+        &self,
+        playing: bool,
+        queue: &PlayQueue,
+        autoplay: &Autoplay,
+        settings: &PlayerSettings,
+    ) -> PlayerStatus {
+        let position = match (self.track.tb, &self.track.last_packet) {
+            (Some(_), Some(_)) => Some(self.position_secs()),
+            _ => None,
+        };
+        PlayerStatus {
+            playing,
+            current_status: position.map(|p| p as u64),
+            // Tenths of a second are precise enough, finer steps would flood clients with status.
+            position: position.map(|p| (p * 10.0).round() / 10.0),
+            total_time: {
+                if let Some(tb) = self.track.tb {
+                    if let Some(packet) = &self.track.last_packet {
+                        if let Some(dur) = self.track.dur {
+                            let d = tb.calc_time(dur);
+                            Some(d.seconds)
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            },
+            shuffle: queue.shuffle(),
+            repeat: queue.repeat(),
+            autoplay: autoplay.enabled(),
+            volume: settings.volume as i32,
+            muted: settings.muted,
+            crossfade: settings.crossfade_secs,
+            speed: settings.speed,
+            mono: settings.channels.mono,
+            balance: settings.channels.balance,
+            output_device: settings.output_device.clone(),
+            metered: settings.download.metered,
+            bandwidth_limit: settings.download.max_bytes_per_sec,
+            track_bandwidth_limit: settings.download.max_track_bytes_per_sec,
+        }
+    }
+
+    fn track_info(&self) -> TrackInfo {
+        TrackInfo {
+            video_id: self.video_id.clone(),
+            title: self.title.clone(),
+            duration: self.track.duration_secs(),
+        }
+    }
+
+    /// Resumes playback, restarting the track if it already ended.
+    fn resume(&mut self) {
+        if self.ended {
+            if let Err(err) = self.seek(0.0) {
+                log::warn!("Cant restart track {:#?}", err);
+            }
+        }
+        self.is_playing = true;
+    }
+
+    /// Stores the loudness measured while playing the whole track next to its cached file, so
+    /// replays can be normalized from the start. Called when the track reached its end, nothing is
+    /// stored if part of it was seeked over.
+    fn save_loudness(&self) {
+        let normalizer = &self.track.normalizer;
+        if !self.unseeked || normalizer.has_stored_measurement() {
+            return;
+        }
+        if let (Some(file_path), Some(info)) = (&self.file_path, normalizer.measurement()) {
+            log::info!("Saving loudness {:#?}", info);
+            info.save(file_path);
+        }
+    }
+
+    /// Position of the audio being heard, in seconds. Until the track reaches the output this is
+    /// the position of the last decoded packet, or of a seek still being decoded to.
+    fn position_secs(&self) -> f64 {
+        if let Some(heard) = self.heard_secs() {
+            return heard;
+        }
+        let track = &self.track;
+        let ts = track.last_packet.as_ref().map(|p| p.pts()).unwrap_or(0);
+        track
+            .ts_to_secs(ts.max(track.play_opts.seek_ts))
+            .unwrap_or(0.0)
+    }
+
+    /// Position of the audio being heard, found by looking up the output clock in the timeline of
+    /// the writes.
+    fn heard_secs(&self) -> Option<f64> {
+        let clock = self.audio_output.as_ref()?.output.played_secs();
+        let &(written, position) = self
+            .timeline
+            .iter()
+            .find(|(written, _)| *written >= clock)
+            .or_else(|| self.timeline.back())?;
+        // Before the first write is heard the previous track may still be playing.
+        Some((position - (written - clock).max(0.0)).max(0.0))
+    }
+
+    /// Seeks to `secs` from the start of the track and returns the position seeked to. Decoding
+    /// restarts at the closest packet before it and the samples ahead of the exact position are
+    /// trimmed once decoded. Audio still queued for the old position is dropped.
+    fn seek(&mut self, secs: f64) -> Result<f64, Error> {
+        let secs = match self.track.duration_secs() {
+            Some(dur) => secs.min(dur),
+            None => secs,
+        }
+        .max(0.0);
+        let seeked_to = self.track.reader.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(secs),
+                track_id: Some(self.track.play_opts.track_id),
+            },
+        )?;
+        log::info!(
+            "Seeked to {} for {}",
+            seeked_to.actual_ts,
+            seeked_to.required_ts
+        );
+        self.track.play_opts.seek_ts = seeked_to.required_ts;
+        self.ended = false;
+        self.unseeked = false;
+        self.track.decoder.reset();
+        self.track.pending = None;
+        self.timeline.clear();
+        if let Some(output) = &mut self.audio_output {
+            output.output.clear();
+        }
+        Ok(self.track.ts_to_secs(seeked_to.required_ts).unwrap_or(secs))
+    }
+
+    /// Closes the output so that it is opened again on the device now chosen, continuing from
+    /// the position being heard.
+    fn reopen_output(&mut self) {
+        if !self.ended {
+            let position = self.position_secs();
+            if let Err(err) = self.seek(position) {
+                log::warn!("Cant seek back after closing the output {:#?}", err);
+            }
+        }
+        self.audio_output = None;
+        self.timeline.clear();
+    }
+
+    /// Plays the next samples of the track. When `next` is given and the end of this track is
+    /// within the crossfade, the head of `next` is mixed in.
+    fn play(
+        &mut self,
+        settings: &PlayerSettings,
+        next: Option<&mut PlayingData>,
+    ) -> Result<(), PlayError> {
+        // Samples decoded ahead go out before anything new is decoded.
+        let from_pending = match (&mut self.track.pending, &mut self.track.processed) {
+            (Some(pending), Some(buf)) => pending.pop_into(buf),
+            _ => false,
+        };
+        if !from_pending {
+            let decoded = self
+                .track
+                .decode_next(settings)
+                .map_err(PlayError::Decode)?;
+            if !decoded {
+                return Ok(());
+            }
+            if let Some(next) = next {
+                self.crossfade_into(next, settings);
+            }
+        }
+        self.open_output(settings).map_err(PlayError::Output)?;
+        self.write_processed().map_err(PlayError::Output)
+    }
+
+    /// Fades this track out and `next` in over the processed samples, once the end of this
+    /// track is within the crossfade. Only the frames of `next` decoded ahead are mixed in, and
+    /// tracks whose signal specs differ are not mixed.
+    fn crossfade_into(&mut self, next: &mut PlayingData, settings: &PlayerSettings) {
+        if settings.crossfade_secs <= 0.0 {
+            return;
+        }
+        let remaining = match self.track.remaining_secs() {
+            Some(remaining) if remaining < settings.crossfade_secs => remaining,
+            _ => return,
+        };
+        let buf = match &mut self.track.processed {
+            Some(buf) => buf,
+            None => return,
+        };
+        let spec = *buf.spec();
+        if let Some(pending) = &mut next.track.pending {
+            if pending.spec() != spec {
+                return;
+            }
+            let progress = 1.0 - remaining / settings.crossfade_secs;
+            let step = 1.0 / (settings.crossfade_secs * spec.rate as f64);
+            pending.mix_into(buf, progress as f32, step as f32);
+        }
+    }
+
+    /// Opens the audio output for the processed samples, reopening it when the output carried
+    /// over from the previous track was opened for other channels, a rate it cant convert or a
+    /// smaller buffer.
+    fn open_output(&mut self, settings: &PlayerSettings) -> Result<(), AudioOutputError> {
+        let buf = match &self.track.processed {
+            Some(buf) => buf,
+            None => return Ok(()),
+        };
+        // Get the audio buffer specification. This is a description of the decoded audio
+        // buffer's sample format and sample rate.
+        let spec = *buf.spec();
+
+        // Get the capacity of the decoded buffer. Note that this is capacity, not length! The
+        // capacity of the decoded buffer is constant for the life of the decoder, but the length
+        // is not.
+        let capacity = buf.capacity() as u64;
+
+        let fits = match &mut self.audio_output {
+            Some(o) if o.spec.channels == spec.channels && o.capacity >= capacity => {
+                // Another rate is converted by the output if it can, without reopening it.
+                if o.spec.rate != spec.rate && o.output.set_input_rate(spec.rate) {
+                    log::info!("Output input rate changed {} -> {}", o.spec.rate, spec.rate);
+                    o.spec = spec;
+                }
+                o.spec == spec
+            }
+            _ => false,
+        };
+        if !fits {
+            log::debug!("Create output");
+            // Close the previous output first so that it releases the device, once it played
+            // what was written to it.
+            self.end_output();
+            if let Some(previous) = &self.audio_output {
+                drain(previous.output.as_ref());
+            }
+            self.audio_output = None;
+            self.timeline.clear();
+            log::debug!("Try open cpal");
+            let mut output = super::output::try_open(
+                &settings.sink,
+                spec,
+                capacity,
+                settings.output_device.as_deref(),
+            )?;
+            settings.apply(output.as_mut());
+            self.audio_output.replace(OpenOutput {
+                output,
+                spec,
+                capacity,
+            });
+            log::debug!("Cpal opened");
+        }
+        Ok(())
+    }
+
+    /// Writes out what the output still holds back, when nothing more follows on it.
+    fn end_output(&mut self) {
+        if let Some(output) = &mut self.audio_output {
+            if let Err(err) = output.output.end_stream() {
+                log::warn!("Cant end output stream {:?}", err);
+            }
+        }
+    }
+
+    /// Writes the processed samples to the output and notes in the timeline where they end on
+    /// the output clock.
+    fn write_processed(&mut self) -> Result<(), AudioOutputError> {
+        if let (Some(audio_output), Some(buf)) = (&mut self.audio_output, &self.track.processed) {
+            // log::info!("Audio output wrting");
+            let output = &mut audio_output.output;
+            output.write(AudioBufferRef::F32(Cow::Borrowed(buf)))?;
+            // Anything still pending was decoded after these samples.
+            let pending = self.track.pending.as_ref().map(|p| p.frames()).unwrap_or(0);
+            let end = self.track.decoded_end_secs - pending as f64 / buf.spec().rate as f64;
+            self.timeline.push_back((output.written_secs(), end));
+            let played = output.played_secs();
+            while self.timeline.len() > 1 && self.timeline[0].0 < played {
+                self.timeline.pop_front();
+            }
+            Ok(())
+        } else {
+            log::warn!("No audio output");
+            Ok(())
+        }
+    }
+}
+
+impl TrackDecoder {
+    fn ts_to_secs(&self, ts: u64) -> Option<f64> {
+        let time = self.tb?.calc_time(ts);
+        Some(time.seconds as f64 + time.frac)
+    }
+
+    fn duration_secs(&self) -> Option<f64> {
+        self.ts_to_secs(self.dur?)
+    }
+
+    /// Time left until the end of the track, measured from the last decoded packet.
+    fn remaining_secs(&self) -> Option<f64> {
+        let tb = self.tb?;
+        let dur = self.dur?;
+        let pts = self.last_packet.as_ref().map(|p| p.pts()).unwrap_or(0);
+        let remaining = tb.calc_time(dur.saturating_sub(pts));
+        Some(remaining.seconds as f64 + remaining.frac)
+    }
+
+    /// Decodes until at least `frames` processed frames are waiting in `pending`.
+    fn fill_pending(&mut self, settings: &PlayerSettings, frames: usize) -> Result<(), Error> {
+        while self.pending.as_ref().map(|p| p.frames()).unwrap_or(0) < frames {
+            if self.decode_next(settings)? {
+                if let Some(buf) = &self.processed {
+                    self.pending
+                        .get_or_insert_with(|| SampleQueue::new(*buf.spec()))
+                        .push(buf);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the first `secs` of the track into `pending`, so that they are ready before the
+    /// track plays.
+    fn decode_ahead(&mut self, settings: &PlayerSettings, secs: f64) -> Result<(), Error> {
+        loop {
+            let (frames, rate) = match &self.pending {
+                Some(pending) => (pending.frames(), pending.spec().rate),
+                None => (0, 0),
+            };
+            if rate > 0 && frames as f64 >= secs * rate as f64 {
+                return Ok(());
+            }
+            self.fill_pending(settings, frames + 1)?;
+        }
+    }
+
+    /// Decodes the next packet like `decode_packet`. When the stream changes its tracks, as
+    /// chained streams do, decoding carries on with the first supported track.
+    fn decode_next(&mut self, settings: &PlayerSettings) -> Result<bool, Error> {
+        match self.decode_packet(settings) {
+            Err(Error::ResetRequired) => {
+                log::info!("Stream reset, selecting a new track");
+                self.reset_track()?;
+                Ok(false)
+            }
+            result => result,
+        }
+    }
+
+    /// Selects the first supported track of the stream and creates a decoder for it. Its signal
+    /// spec may differ, the output is reopened or reconfigured for it once decoded.
+    fn reset_track(&mut self) -> Result<(), Error> {
+        let track = first_supported_track(self.reader.tracks())
+            .ok_or(Error::Unsupported("no supported track after reset"))?;
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions { verify: false })?;
+        let play_opts = PlayTrackOptions {
+            track_id: track.id,
+            seek_ts: 0,
+        };
+        let tb = track.codec_params.time_base;
+        let dur = track
+            .codec_params
+            .n_frames
+            .map(|frames| track.codec_params.start_ts + frames);
+        self.decoder = decoder;
+        self.play_opts = play_opts;
+        self.tb = tb;
+        self.dur = dur;
+        Ok(())
+    }
+
+    /// Reads and decodes the next packet of the track into `processed` and runs the processing
+    /// stages on it. Returns false if the packet left nothing to play.
+    fn decode_packet(&mut self, settings: &PlayerSettings) -> Result<bool, Error> {
         // log::info!("Play");
         let decoder = &mut self.decoder;
+        let processed = &mut self.processed;
+        let normalizer = &mut self.normalizer;
+        let equalizer = &mut self.equalizer;
+        let channel_mixer = &mut self.channel_mixer;
+        let limiter = &mut self.limiter;
         // let packet = &mut self.packet;
-        let audio_output = &mut self.audio_output;
         let play_opts = &mut self.play_opts;
         let no_progress = &mut self.no_progress;
         let dur = &mut self.dur;
@@ -381,7 +1467,7 @@ impl PlayingData {
         log::debug!("Check player track");
         // If the packet does not belong to the selected track, skip it.
         if packet.track_id() != play_opts.track_id {
-            return Ok(());
+            return Ok(false);
         }
 
         log::debug!("Get metadata");
@@ -394,32 +1480,28 @@ impl PlayingData {
             }
         }
         log::debug!("Decode packet");
+        let mut decoded_secs = None;
         let r = match decoder.decode(&packet) {
             Ok(decoded) => {
                 log::debug!("Decoded packet");
-                // If the audio output is not open, try to open it.
-                if audio_output.is_none() {
-                    log::debug!("Create output");
-                    // Get the audio buffer specification. This is a description of the decoded
-                    // audio buffer's sample format and sample rate.
-                    let spec = *decoded.spec();
-
-                    // Get the capacity of the decoded buffer. Note that this is capacity, not
-                    // length! The capacity of the decoded buffer is constant for the life of the
-                    // decoder, but the length is not.
-                    let duration = decoded.capacity() as u64;
-
-                    // Try to open the audio output.
-                    log::debug!("Try open cpal");
-                    audio_output.replace(super::output::try_open(spec, duration).unwrap());
-                    log::debug!("Cpal opened");
+                // Frames of the packet that come before the seeked position (0 if not seeking).
+                let skip_frames = if packet.pts() < play_opts.seek_ts {
+                    match tb {
+                        Some(tb) => {
+                            let skipped = tb.calc_time(play_opts.seek_ts - packet.pts());
+                            ((skipped.seconds as f64 + skipped.frac) * decoded.spec().rate as f64)
+                                .round() as usize
+                        }
+                        None => decoded.frames(),
+                    }
                 } else {
-                    // TODO: Check the audio spec. and duration hasn't changed.
-                }
-
-                // Write the decoded audio samples to the audio output if the presentation timestamp
-                // for the packet is >= the seeked position (0 if not seeking).
-                if packet.pts() >= play_opts.seek_ts {
+                    0
+                };
+                // Process the decoded audio samples if any of them are at or past the seeked
+                // position.
+                let ready = skip_frames < decoded.frames();
+                if ready {
+                    decoded_secs = Some(decoded.frames() as f64 / decoded.spec().rate as f64);
                     // if let Some(tb) = tb {
                     //     let t = tb.calc_time(packet.pts()).seconds;
                     //     // if t > 50 {
@@ -459,21 +1541,39 @@ impl PlayingData {
                         print_progress(packet.pts(), *dur, *tb);
                     }
 
-                    if let Some(audio_output) = audio_output {
-                        // log::info!("Audio output wrting");
-                        audio_output.write(decoded).unwrap()
-                    } else {
-                        log::warn!("No audio output");
+                    let stale = processed
+                        .as_ref()
+                        .map(|buf| {
+                            buf.capacity() < decoded.capacity() || buf.spec() != decoded.spec()
+                        })
+                        .unwrap_or(true);
+                    if stale {
+                        *processed = Some(decoded.make_equivalent::<f32>());
+                    }
+
+                    if let Some(buf) = processed.as_mut() {
+                        decoded.convert(buf);
+                        trim_start(buf, skip_frames);
+                        normalizer.set_options(settings.normalization);
+                        normalizer.process(buf);
+                        equalizer.set_options(&settings.equalizer);
+                        equalizer.process(buf);
+                        channel_mixer.set_options(settings.channels);
+                        channel_mixer.process(buf);
+                        // Last, so that nothing adds gain after it but the output volume, which
+                        // it allows for.
+                        limiter.set_options(settings.normalization, settings.volume as f32 / 100.0);
+                        limiter.process(buf);
                     }
                 }
                 // log::info!("next frame");
-                Ok(())
+                Ok(ready)
             }
-            Err(symphonia::core::errors::Error::DecodeError(err)) => {
+            Err(Error::DecodeError(err)) => {
                 // Decode errors are not fatal. Print the error message and try to decode the next
                 // packet as usual.
                 log::warn!("decode error: {}", err);
-                Ok(())
+                Ok(false)
             }
             Err(err) => {
                 log::warn!("{:#?}", err);
@@ -481,11 +1581,31 @@ impl PlayingData {
             }
         };
 
+        if let Some(secs) = decoded_secs {
+            self.decoded_end_secs = self.ts_to_secs(packet.pts()).unwrap_or(0.0) + secs;
+        }
         self.last_packet = Some(packet);
         r
     }
 }
 
+/// Longest wait for an output to play what was written before it is closed.
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Waits until `output` played everything written to it.
+fn drain(output: &dyn AudioOutput) {
+    let start = std::time::Instant::now();
+    while output.played_secs() < output.written_secs() && start.elapsed() < DRAIN_TIMEOUT {
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+}
+
+/// Why a track stopped playing.
+enum PlayError {
+    Decode(Error),
+    Output(AudioOutputError),
+}
+
 fn first_supported_track(tracks: &[Track]) -> Option<&Track> {
     tracks
         .iter()
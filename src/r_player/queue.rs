@@ -0,0 +1,469 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::server::schema::{PlayOptions, QueueItem, QueueStatus, RepeatMode};
+
+/// Tracks owned by the player, with a cursor on the one currently playing.
+///
+/// `items` keeps the order the tracks were added in, `order` is the order they are played in.
+/// Without shuffle `order` is the identity permutation.
+#[derive(Debug)]
+pub struct PlayQueue {
+    items: Vec<PlayOptions>,
+    order: Vec<usize>,
+    position: Option<usize>,
+    shuffle: bool,
+    repeat: RepeatMode,
+    rng: StdRng,
+    /// Tracks that failed to play one after the other, since one last played to its end.
+    failures: usize,
+}
+
+impl Default for PlayQueue {
+    fn default() -> Self {
+        Self::with_seed(rand::thread_rng().gen())
+    }
+}
+
+impl PlayQueue {
+    /// Creates an empty queue whose shuffle order is derived from `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            items: vec![],
+            order: vec![],
+            position: None,
+            shuffle: false,
+            repeat: RepeatMode::Off,
+            rng: StdRng::seed_from_u64(seed),
+            failures: 0,
+        }
+    }
+
+    fn current_index(&self) -> Option<usize> {
+        self.position.and_then(|p| self.order.get(p)).copied()
+    }
+
+    pub fn current(&self) -> Option<&PlayOptions> {
+        self.current_index().and_then(|i| self.items.get(i))
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    /// Turns shuffle on or off. The current track stays current, and tracks already played keep
+    /// their place so that previous still walks back through them. A `seed` makes the resulting
+    /// permutation reproducible.
+    pub fn set_shuffle(&mut self, shuffle: bool, seed: Option<u64>) {
+        if let Some(seed) = seed {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
+        let current = self.current_index();
+        self.shuffle = shuffle;
+        if shuffle {
+            let split = self.position.map(|p| p + 1).unwrap_or(0);
+            self.order[split..].shuffle(&mut self.rng);
+        } else {
+            self.order = (0..self.items.len()).collect();
+            self.position = current;
+        }
+    }
+
+    /// Appends a track at the end of the queue. With shuffle on it lands at a random spot among
+    /// the tracks not played yet.
+    pub fn enqueue(&mut self, options: PlayOptions) {
+        self.items.push(options);
+        let index = self.items.len() - 1;
+        if self.shuffle {
+            let start = self.position.map(|p| p + 1).unwrap_or(0);
+            let pos = self.rng.gen_range(start..=self.order.len());
+            self.order.insert(pos, index);
+        } else {
+            self.order.push(index);
+        }
+    }
+
+    /// Inserts a track right after the current one.
+    pub fn insert_next(&mut self, options: PlayOptions) {
+        let index = self.current_index().map(|i| i + 1).unwrap_or(0);
+        self.items.insert(index, options);
+        for i in self.order.iter_mut() {
+            if *i >= index {
+                *i += 1;
+            }
+        }
+        let pos = self.position.map(|p| p + 1).unwrap_or(0);
+        self.order.insert(pos, index);
+    }
+
+    /// Inserts a track right after the current one and makes it current.
+    pub fn play_now(&mut self, options: PlayOptions) -> Option<&PlayOptions> {
+        self.insert_next(options);
+        self.position = Some(self.position.map(|p| p + 1).unwrap_or(0));
+        self.current()
+    }
+
+    /// Removes the track at `index`. Returns true if it was the current track, in which case the
+    /// track that took its place (if any) is now current.
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.items.len() {
+            return false;
+        }
+        let was_current = self.current_index() == Some(index);
+        self.items.remove(index);
+        if let Some(pos) = self.order.iter().position(|i| *i == index) {
+            self.order.remove(pos);
+            if let Some(p) = self.position {
+                if pos < p {
+                    self.position = Some(p - 1);
+                } else if pos == p && p >= self.order.len() {
+                    self.position = None;
+                }
+            }
+        }
+        for i in self.order.iter_mut() {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+        was_current
+    }
+
+    /// Moves the track at `from` to `to`, keeping the cursor on the same track.
+    pub fn move_item(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.items.len() || to >= self.items.len() {
+            return false;
+        }
+        let item = self.items.remove(from);
+        self.items.insert(to, item);
+        for i in self.order.iter_mut() {
+            *i = if *i == from {
+                to
+            } else if from < *i && to >= *i {
+                *i - 1
+            } else if from > *i && to <= *i {
+                *i + 1
+            } else {
+                *i
+            };
+        }
+        if !self.shuffle {
+            // Without shuffle the play order follows the list order.
+            let current = self.current_index();
+            self.order = (0..self.items.len()).collect();
+            self.position = current;
+        }
+        true
+    }
+
+    /// Removes every track except the one currently playing.
+    pub fn clear(&mut self) {
+        match self.current_index() {
+            Some(cur) => {
+                let item = self.items.swap_remove(cur);
+                self.items = vec![item];
+                self.order = vec![0];
+                self.position = Some(0);
+            }
+            None => {
+                self.items.clear();
+                self.order.clear();
+                self.position = None;
+            }
+        }
+    }
+
+    /// The track `advance` would move to, without moving the cursor.
+    pub fn peek_advance(&self) -> Option<&PlayOptions> {
+        if self.repeat == RepeatMode::One && self.current_index().is_some() {
+            return self.current();
+        }
+        let next = self.position.map(|p| p + 1).unwrap_or(0);
+        if next < self.order.len() {
+            self.items.get(self.order[next])
+        } else if self.repeat == RepeatMode::All && !self.order.is_empty() {
+            self.items.get(self.order[0])
+        } else {
+            None
+        }
+    }
+
+    /// Moves to the track that should follow the current one when it finishes playing, honoring
+    /// the repeat mode.
+    pub fn advance(&mut self) -> Option<&PlayOptions> {
+        self.failures = 0;
+        if self.repeat == RepeatMode::One && self.current_index().is_some() {
+            return self.current();
+        }
+        self.next()
+    }
+
+    /// Moves on from the current track after it failed to play. Unlike `advance` the track is
+    /// never repeated, and once every track of the queue failed in a row there is nothing left to
+    /// try.
+    pub fn skip_failed(&mut self) -> Option<&PlayOptions> {
+        self.failures += 1;
+        if self.failures >= self.items.len() {
+            return None;
+        }
+        self.next()
+    }
+
+    /// Moves the cursor to the next track, returning it if there is one. At the end of the queue
+    /// it wraps around with repeat-all, otherwise the cursor stays on the last track.
+    pub fn next(&mut self) -> Option<&PlayOptions> {
+        let next = self.position.map(|p| p + 1).unwrap_or(0);
+        if next < self.order.len() {
+            self.position = Some(next);
+            self.current()
+        } else if self.repeat == RepeatMode::All && !self.order.is_empty() {
+            self.position = Some(0);
+            self.current()
+        } else {
+            None
+        }
+    }
+
+    /// Moves the cursor to the previous track. At the start of the queue it wraps around with
+    /// repeat-all, otherwise the first track is returned again so that it restarts.
+    pub fn previous(&mut self) -> Option<&PlayOptions> {
+        let prev = match self.position {
+            Some(0) if self.repeat == RepeatMode::All => self.order.len().saturating_sub(1),
+            Some(p) => p.saturating_sub(1),
+            None => 0,
+        };
+        if prev < self.order.len() {
+            self.position = Some(prev);
+            self.current()
+        } else {
+            None
+        }
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        QueueStatus {
+            items: self
+                .items
+                .iter()
+                .map(|item| QueueItem {
+                    video_id: item.video_id.clone(),
+                    title: item.title.clone(),
+                    url: item.url.clone(),
+                })
+                .collect(),
+            current: self.current_index().map(|i| i as i32),
+            order: self.order.iter().map(|i| *i as i32).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(video_id: &str) -> PlayOptions {
+        PlayOptions {
+            video_id: video_id.to_string(),
+            title: None,
+            url: format!("https://example.com/{}", video_id),
+            length: None,
+            file_path: None,
+        }
+    }
+
+    fn queue(video_ids: &[&str]) -> PlayQueue {
+        let mut queue = PlayQueue::with_seed(0);
+        for video_id in video_ids {
+            queue.enqueue(track(video_id));
+        }
+        queue
+    }
+
+    /// Video ids in the order they are played.
+    fn play_order(queue: &PlayQueue) -> Vec<String> {
+        let status = queue.status();
+        status
+            .order
+            .iter()
+            .map(|i| status.items[*i as usize].video_id.clone())
+            .collect()
+    }
+
+    fn current(queue: &PlayQueue) -> Option<&str> {
+        queue.current().map(|c| c.video_id.as_str())
+    }
+
+    #[test]
+    fn same_seed_shuffles_the_same() {
+        let ids = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+        let mut first = queue(&ids);
+        let mut second = queue(&ids);
+        first.set_shuffle(true, Some(42));
+        second.set_shuffle(true, Some(42));
+        assert_eq!(play_order(&first), play_order(&second));
+
+        let mut order = first.status().order;
+        order.sort_unstable();
+        assert_eq!(order, (0..10).collect::<Vec<_>>());
+
+        let mut other = queue(&ids);
+        other.set_shuffle(true, Some(7));
+        assert_ne!(play_order(&first), play_order(&other));
+    }
+
+    #[test]
+    fn shuffle_keeps_played_tracks_in_place() {
+        let mut queue = queue(&["a", "b", "c", "d", "e"]);
+        queue.next();
+        queue.next();
+        queue.set_shuffle(true, Some(3));
+        assert_eq!(&play_order(&queue)[..2], ["a", "b"]);
+        assert_eq!(current(&queue), Some("b"));
+
+        queue.set_shuffle(false, None);
+        assert_eq!(play_order(&queue), ["a", "b", "c", "d", "e"]);
+        assert_eq!(current(&queue), Some("b"));
+    }
+
+    #[test]
+    fn insert_next_plays_after_the_current_track() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.next();
+        queue.insert_next(track("x"));
+        assert_eq!(play_order(&queue), ["a", "x", "b", "c"]);
+        assert_eq!(current(&queue), Some("a"));
+        assert_eq!(queue.next().map(|n| n.video_id.as_str()), Some("x"));
+    }
+
+    #[test]
+    fn insert_next_into_an_empty_queue() {
+        let mut queue = PlayQueue::with_seed(0);
+        queue.insert_next(track("x"));
+        assert_eq!(current(&queue), None);
+        assert_eq!(queue.next().map(|n| n.video_id.as_str()), Some("x"));
+    }
+
+    #[test]
+    fn remove_keeps_the_cursor_on_the_current_track() {
+        let mut queue = queue(&["a", "b", "c", "d"]);
+        queue.next();
+        queue.next();
+        assert!(!queue.remove(0));
+        assert_eq!(current(&queue), Some("b"));
+        assert_eq!(queue.status().current, Some(0));
+        assert!(!queue.remove(10));
+        assert_eq!(play_order(&queue), ["b", "c", "d"]);
+    }
+
+    #[test]
+    fn removing_the_current_track_moves_to_the_next() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.next();
+        assert!(queue.remove(0));
+        assert_eq!(current(&queue), Some("b"));
+
+        queue.next();
+        assert!(queue.remove(1));
+        assert_eq!(current(&queue), None);
+        assert_eq!(play_order(&queue), ["b"]);
+    }
+
+    #[test]
+    fn move_item_keeps_the_cursor_on_the_same_track() {
+        let mut queue = queue(&["a", "b", "c", "d"]);
+        queue.next();
+        assert!(queue.move_item(0, 2));
+        assert_eq!(play_order(&queue), ["b", "c", "a", "d"]);
+        assert_eq!(current(&queue), Some("a"));
+
+        assert!(queue.move_item(3, 0));
+        assert_eq!(play_order(&queue), ["d", "b", "c", "a"]);
+        assert_eq!(current(&queue), Some("a"));
+        assert!(!queue.move_item(0, 4));
+    }
+
+    #[test]
+    fn failed_track_is_not_repeated() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.set_repeat(RepeatMode::One);
+        queue.next();
+        assert_eq!(queue.skip_failed().map(|n| n.video_id.as_str()), Some("b"));
+        assert_eq!(queue.advance().map(|n| n.video_id.as_str()), Some("b"));
+    }
+
+    #[test]
+    fn repeat_all_stops_once_every_track_failed() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.set_repeat(RepeatMode::All);
+        queue.next();
+        assert_eq!(queue.skip_failed().map(|n| n.video_id.as_str()), Some("b"));
+        assert_eq!(queue.skip_failed().map(|n| n.video_id.as_str()), Some("c"));
+        assert!(queue.skip_failed().is_none());
+    }
+
+    #[test]
+    fn track_played_to_its_end_resets_the_failures() {
+        let mut queue = queue(&["a", "b"]);
+        queue.set_repeat(RepeatMode::All);
+        queue.next();
+        assert_eq!(queue.skip_failed().map(|n| n.video_id.as_str()), Some("b"));
+        assert_eq!(queue.advance().map(|n| n.video_id.as_str()), Some("a"));
+        assert_eq!(queue.skip_failed().map(|n| n.video_id.as_str()), Some("b"));
+    }
+
+    #[test]
+    fn clear_keeps_the_current_track() {
+        let mut playing = queue(&["a", "b", "c"]);
+        playing.next();
+        playing.next();
+        playing.clear();
+        assert_eq!(play_order(&playing), ["b"]);
+        assert_eq!(current(&playing), Some("b"));
+
+        let mut stopped = queue(&["a", "b"]);
+        stopped.clear();
+        assert!(play_order(&stopped).is_empty());
+        assert_eq!(current(&stopped), None);
+    }
+
+    #[test]
+    fn next_stops_at_the_end_of_the_queue() {
+        let mut queue = queue(&["a", "b"]);
+        queue.next();
+        assert_eq!(queue.next().map(|n| n.video_id.as_str()), Some("b"));
+        assert!(queue.next().is_none());
+        assert_eq!(current(&queue), Some("b"));
+    }
+
+    #[test]
+    fn previous_walks_back_and_restarts_the_first_track() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.next();
+        queue.next();
+        queue.next();
+        assert_eq!(queue.previous().map(|p| p.video_id.as_str()), Some("b"));
+        assert_eq!(queue.previous().map(|p| p.video_id.as_str()), Some("a"));
+        assert_eq!(queue.previous().map(|p| p.video_id.as_str()), Some("a"));
+    }
+
+    #[test]
+    fn previous_wraps_around_with_repeat_all() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.set_repeat(RepeatMode::All);
+        queue.next();
+        assert_eq!(queue.previous().map(|p| p.video_id.as_str()), Some("c"));
+    }
+
+    #[test]
+    fn previous_of_an_empty_queue() {
+        let mut queue = PlayQueue::with_seed(0);
+        assert!(queue.previous().is_none());
+    }
+}
@@ -29,11 +29,20 @@ pub async fn run_server(
     let storage = Storage {
         to_player_message: Arc::new(Mutex::new(msg_sender)),
         from_player_message: Arc::new(Mutex::new(None)),
+        equalizer: Arc::new(Mutex::new(None)),
+        queue: Arc::new(Mutex::new(None)),
     };
     let sc = storage.clone();
 
     let receiver_task = async {
         while let Some(msg) = msg_receiver.next().await {
+            match &msg {
+                PlayerMessage::Equalizer(status) => {
+                    *sc.equalizer.lock().await = Some(status.clone());
+                }
+                PlayerMessage::Queue(status) => *sc.queue.lock().await = Some(status.clone()),
+                _ => {}
+            }
             if let Some(sen) = &mut *sc.from_player_message.lock().await {
                 let out = sen.send(msg).await;
                 match out {
@@ -11,7 +11,13 @@ use rusty_pipe::{downloader_trait::Downloader, youtube_extractor::{
     search_extractor::YTSearchExtractor, stream_extractor::YTStreamExtractor,
 }};
 
-use crate::yt_downloader::YTDownloader;
+use crate::{
+    cache::{CacheEntry, CacheManager},
+    config::Config,
+    downloader::cache::RangeIndex,
+    dsp::{equalizer::EqualizerOptions, loudness::NormalizationOptions},
+    yt_downloader::YTDownloader,
+};
 
 use super::{search::Search, stream::Video};
 
@@ -20,32 +26,275 @@ pub enum ToPlayerMessages {
     Play(PlayOptions),
     Resume,
     Pause,
+    /// Seeks by this many seconds from the current position.
     Seek(i64),
+    /// Seeks to this many seconds from the start of the track.
+    SeekTo(f64),
+    /// Seeks to this percentage of the track length.
+    SeekPercent(f64),
+    Enqueue(PlayOptions),
+    PlayNext(PlayOptions),
+    RemoveFromQueue(usize),
+    MoveInQueue(usize, usize),
+    ClearQueue,
+    Next,
+    Previous,
+    SetShuffle(bool, Option<u64>),
+    SetRepeat(RepeatMode),
+    SetAutoplay(AutoplayOptions),
+    /// Volume in percent, from 0 to 150.
+    SetVolume(u8),
+    Mute(bool),
+    SetNormalization(NormalizationOptions),
+    /// Crossfade between consecutive tracks in seconds, 0 to switch without one.
+    SetCrossfade(f64),
+    /// Playback rate, from 0.5 to 3.0.
+    SetSpeed(f64),
+    EnableEqualizer(bool),
+    SetEqualizer(EqualizerOptions),
+    /// Loads the built-in or user preset with this name.
+    SetEqualizerPreset(String),
+    /// Sets the gain of one band in dB.
+    SetEqualizerBand(usize, f64),
+    /// Plays the average of all channels on every channel.
+    SetMono(bool),
+    /// Sets the left/right balance, from -1 for left only to 1 for right only.
+    SetBalance(f64),
+    /// Plays on the output device with this name, or on the default device.
+    SetOutputDevice(Option<String>),
+    /// Caps the bandwidth of all downloads together and of each download, in bytes per second.
+    SetBandwidthLimit(Option<u64>, Option<u64>),
+    /// Reads ahead a little only and picks the lowest bitrate streams, for metered connections.
+    SetMetered(bool),
 }
 #[derive(Debug, Clone)]
 pub struct PlayOptions {
     pub video_id: String,
+    pub title: Option<String>,
     pub url: String,
     pub length: Option<usize>,
     pub file_path: Option<String>,
 }
 
+#[derive(Debug, Clone)]
+pub struct AutoplayOptions {
+    pub enabled: bool,
+    /// How many recently played videos autoplay avoids picking again.
+    pub history_size: usize,
+    /// Only pick related videos at most this many seconds long.
+    pub max_duration: Option<u64>,
+}
+
+impl Default for AutoplayOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            history_size: 50,
+            max_duration: None,
+        }
+    }
+}
+
 #[derive(Union, PartialEq, Clone)]
 pub enum PlayerMessage {
     Status(PlayerStatus),
+    Queue(QueueStatus),
+    Equalizer(EqualizerStatus),
+    Seek(SeekStatus),
+    State(StateStatus),
+    TrackChanged(TrackInfo),
+    Buffering(BufferingStatus),
+    Error(PlayerError),
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PlayerState {
+    /// Nothing is loaded.
+    Idle,
+    /// A track is being opened.
+    Loading,
+    /// Playback is waiting for data from the network.
+    Buffering,
+    Playing,
+    Paused,
+    /// The last track finished and nothing follows it.
+    Ended,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct StateStatus {
+    pub state: PlayerState,
+    pub video_id: Option<String>,
+}
+
+/// Sent whenever a track starts playing.
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct TrackInfo {
+    pub video_id: String,
+    pub title: Option<String>,
+    /// Length of the track in seconds, when known.
+    pub duration: Option<f64>,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct BufferingStatus {
+    pub video_id: String,
+    /// Share of the audio downloaded so far.
+    pub percent: f64,
+    /// Download speed over the last seconds.
+    pub bytes_per_sec: f64,
+    /// Seconds of audio downloaded ahead of the position read.
+    pub buffered_secs: f64,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PlayerErrorKind {
+    /// The video could not be resolved to an audio stream.
+    Extractor,
+    /// Downloading the audio failed.
+    Network,
+    /// The audio could not be opened or decoded.
+    Decode,
+    /// The audio device failed.
+    Output,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct PlayerError {
+    pub kind: PlayerErrorKind,
+    pub message: String,
+    pub video_id: Option<String>,
 }
 
 #[derive(SimpleObject, PartialEq, Clone)]
 pub struct PlayerStatus {
     pub playing: bool,
     pub current_status: Option<u64>,
+    /// Position of the audio being heard, in seconds.
+    pub position: Option<f64>,
     pub total_time: Option<u64>,
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+    pub autoplay: bool,
+    /// Volume in percent, 100 being unity gain.
+    pub volume: i32,
+    pub muted: bool,
+    /// Crossfade between consecutive tracks in seconds.
+    pub crossfade: f64,
+    /// Playback rate, 1.0 being normal speed.
+    pub speed: f64,
+    pub mono: bool,
+    /// Left/right balance, from -1 for left only to 1 for right only.
+    pub balance: f64,
+    /// Output device chosen, none for the default device.
+    pub output_device: Option<String>,
+    pub metered: bool,
+    /// Cap on the bandwidth of all downloads together, in bytes per second.
+    pub bandwidth_limit: Option<u64>,
+    /// Cap on the bandwidth of each download, in bytes per second.
+    pub track_bandwidth_limit: Option<u64>,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RepeatMode {
+    Off,
+    One,
+    All,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct QueueStatus {
+    pub items: Vec<QueueItem>,
+    pub current: Option<i32>,
+    /// Indexes into `items` in the order they will be played.
+    pub order: Vec<i32>,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct QueueItem {
+    pub video_id: String,
+    pub title: Option<String>,
+    pub url: String,
+}
+
+/// Outcome of a seek request.
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct SeekStatus {
+    pub success: bool,
+    /// Position seeked to, in seconds.
+    pub position: Option<f64>,
+    pub error: Option<String>,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct EqualizerStatus {
+    pub enabled: bool,
+    /// Preset the bands were set from, unless they were edited since.
+    pub preset: Option<String>,
+    pub bands: Vec<EqualizerBand>,
+    /// Names of the built-in and user presets.
+    pub presets: Vec<String>,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct OutputDevice {
+    pub name: String,
+    pub is_default: bool,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct CachedTrack {
+    pub video_id: String,
+    /// Size on disk in bytes.
+    pub size: u64,
+    /// When the track was last played, in seconds since the epoch.
+    pub last_access: u64,
+    /// Pinned tracks are never evicted.
+    pub pinned: bool,
+    /// False while the download is partial.
+    pub complete: bool,
+}
+
+impl From<CacheEntry> for CachedTrack {
+    fn from(entry: CacheEntry) -> Self {
+        Self {
+            video_id: entry.video_id,
+            size: entry.size,
+            last_access: entry.last_access,
+            pinned: entry.pinned,
+            complete: entry.complete,
+        }
+    }
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct CacheStatus {
+    /// Cached tracks, played most recently first.
+    pub tracks: Vec<CachedTrack>,
+    /// Total size on disk in bytes.
+    pub size: u64,
+    /// Size limit in bytes, none if unbounded.
+    pub max_size: Option<u64>,
+    /// Tracks not played for this many days are evicted, none if they are kept.
+    pub max_age_days: Option<u64>,
+}
+
+#[derive(SimpleObject, PartialEq, Clone)]
+pub struct EqualizerBand {
+    /// Center frequency in Hz.
+    pub frequency: f64,
+    /// Gain in dB.
+    pub gain: f64,
 }
 
 #[derive(Clone)]
 pub struct Storage {
     pub to_player_message: Arc<Mutex<Sender<ToPlayerMessages>>>,
     pub from_player_message: Arc<Mutex<Option<Sender<PlayerMessage>>>>,
+    /// Last equalizer state reported by the player.
+    pub equalizer: Arc<Mutex<Option<EqualizerStatus>>>,
+    /// Last queue reported by the player.
+    pub queue: Arc<Mutex<Option<QueueStatus>>>,
 }
 
 pub struct QueryRoot {
@@ -74,44 +323,381 @@ impl QueryRoot {
         video_id: String,
         url: String,
         file_path: Option<String>,
+        title: Option<String>,
     ) -> Result<bool, Error> {
         log::info!("Get storage");
         let data = ctx.data::<Storage>()?;
-        log::info!("Get length");
-        let mut length = {
-            if let Ok(url) = surf::Url::parse(&url) {
-                let response = surf::get(&url).send().await;
-                response.ok().and_then(|r| r.len())
-            } else {
-                None
-            }
-        };
-
-        if length.is_none() {
-            if let Some(file_path) = &file_path {
-                if let Ok(file) = async_std::fs::File::open(&file_path).await {
-                    if let Ok(metadata) = file.metadata().await {
-                        length = Some(metadata.len() as usize);
-                    }
-                }
-            }
-        }
+        let options = play_options(video_id, title, url, file_path).await;
 
         log::info!("Try to lock to_player_msg");
 
         let mut to_player_msg = data.to_player_message.lock().await;
         log::info!("Locked player messages");
+        to_player_msg.send(ToPlayerMessages::Play(options)).await?;
+        Ok(true)
+    }
+
+    async fn enqueue<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        video_id: String,
+        url: String,
+        file_path: Option<String>,
+        title: Option<String>,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let options = play_options(video_id, title, url, file_path).await;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::Enqueue(options))
+            .await?;
+        Ok(true)
+    }
+
+    async fn play_next<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        video_id: String,
+        url: String,
+        file_path: Option<String>,
+        title: Option<String>,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let options = play_options(video_id, title, url, file_path).await;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::PlayNext(options))
+            .await?;
+        Ok(true)
+    }
+
+    async fn remove_from_queue<'ctx>(&self, ctx: &Context<'_>, index: i32) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::RemoveFromQueue(index.max(0) as usize))
+            .await?;
+        Ok(true)
+    }
+
+    async fn move_in_queue<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        from: i32,
+        to: i32,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
         to_player_msg
-            .send(ToPlayerMessages::Play(PlayOptions {
-                video_id,
-                url,
-                length,
-                file_path,
+            .send(ToPlayerMessages::MoveInQueue(
+                from.max(0) as usize,
+                to.max(0) as usize,
+            ))
+            .await?;
+        Ok(true)
+    }
+
+    async fn clear_queue<'ctx>(&self, ctx: &Context<'_>) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg.send(ToPlayerMessages::ClearQueue).await?;
+        Ok(true)
+    }
+
+    async fn next<'ctx>(&self, ctx: &Context<'_>) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg.send(ToPlayerMessages::Next).await?;
+        Ok(true)
+    }
+
+    async fn previous<'ctx>(&self, ctx: &Context<'_>) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg.send(ToPlayerMessages::Previous).await?;
+        Ok(true)
+    }
+
+    async fn set_shuffle<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        shuffle: bool,
+        seed: Option<u64>,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetShuffle(shuffle, seed))
+            .await?;
+        Ok(true)
+    }
+
+    async fn set_autoplay<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        enabled: bool,
+        #[graphql(default = 50)] history_size: i32,
+        max_duration: Option<i32>,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetAutoplay(AutoplayOptions {
+                enabled,
+                history_size: history_size.max(0) as usize,
+                max_duration: max_duration.map(|d| d.max(0) as u64),
             }))
             .await?;
         Ok(true)
     }
 
+    async fn set_volume<'ctx>(&self, ctx: &Context<'_>, volume: i32) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetVolume(volume.max(0).min(150) as u8))
+            .await?;
+        Ok(true)
+    }
+
+    async fn mute<'ctx>(&self, ctx: &Context<'_>, muted: bool) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg.send(ToPlayerMessages::Mute(muted)).await?;
+        Ok(true)
+    }
+
+    async fn set_normalization<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        enabled: bool,
+        #[graphql(default = -14.0)] target_lufs: f64,
+        #[graphql(default = -1.0)] true_peak_db: f64,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetNormalization(NormalizationOptions {
+                enabled,
+                target_lufs,
+                true_peak_db,
+            }))
+            .await?;
+        Ok(true)
+    }
+
+    async fn set_crossfade<'ctx>(&self, ctx: &Context<'_>, seconds: f64) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetCrossfade(seconds))
+            .await?;
+        Ok(true)
+    }
+
+    async fn set_speed<'ctx>(&self, ctx: &Context<'_>, speed: f64) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetSpeed(speed))
+            .await?;
+        Ok(true)
+    }
+
+    async fn set_mono<'ctx>(&self, ctx: &Context<'_>, mono: bool) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg.send(ToPlayerMessages::SetMono(mono)).await?;
+        Ok(true)
+    }
+
+    async fn set_balance<'ctx>(&self, ctx: &Context<'_>, balance: f64) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetBalance(balance))
+            .await?;
+        Ok(true)
+    }
+
+    /// Caps the download bandwidth in bytes per second, of all downloads together and of each
+    /// one. No value removes the cap.
+    async fn set_bandwidth_limit<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        total: Option<u64>,
+        per_track: Option<u64>,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetBandwidthLimit(total, per_track))
+            .await?;
+        Ok(true)
+    }
+
+    async fn set_metered<'ctx>(&self, ctx: &Context<'_>, metered: bool) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetMetered(metered))
+            .await?;
+        Ok(true)
+    }
+
+    async fn output_devices<'ctx>(&self, _ctx: &Context<'_>) -> Vec<OutputDevice> {
+        let default = crate::output::default_output_device();
+        crate::output::output_devices()
+            .into_iter()
+            .map(|name| OutputDevice {
+                is_default: default.as_ref() == Some(&name),
+                name,
+            })
+            .collect()
+    }
+
+    async fn cache<'ctx>(&self, _ctx: &Context<'_>) -> Result<CacheStatus, Error> {
+        let cache = open_cache()?;
+        let limits = Config::load().cache;
+        let tracks = cache
+            .entries()
+            .into_iter()
+            .map(CachedTrack::from)
+            .collect::<Vec<_>>();
+        Ok(CacheStatus {
+            size: tracks.iter().map(|t| t.size).sum(),
+            tracks,
+            max_size: limits.max_bytes(),
+            max_age_days: limits.max_age_days,
+        })
+    }
+
+    /// Deletes every cached track that is not pinned, playing or queued, returning how many were
+    /// deleted.
+    async fn clear_cache<'ctx>(&self, ctx: &Context<'_>) -> Result<i32, Error> {
+        let data = ctx.data::<Storage>()?;
+        let keep = match &*data.queue.lock().await {
+            Some(queue) => queue
+                .items
+                .iter()
+                .map(|item| item.video_id.clone())
+                .collect(),
+            None => vec![],
+        };
+        Ok(open_cache()?.clear(&keep) as i32)
+    }
+
+    /// Pins or unpins a cached track. Returns false if it is not cached.
+    async fn pin_cached_track<'ctx>(
+        &self,
+        _ctx: &Context<'_>,
+        video_id: String,
+        pinned: bool,
+    ) -> Result<bool, Error> {
+        Ok(open_cache()?.pin(&video_id, pinned))
+    }
+
+    /// Deletes a cached track, pinned or not. Returns false if it was not cached.
+    async fn remove_cached_track<'ctx>(
+        &self,
+        _ctx: &Context<'_>,
+        video_id: String,
+    ) -> Result<bool, Error> {
+        Ok(open_cache()?.remove(&video_id))
+    }
+
+    /// Switches playback to the output device with this name, or to the default device when no
+    /// name is given. A device that cant be found falls back to the default.
+    async fn set_output_device<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        name: Option<String>,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetOutputDevice(name))
+            .await?;
+        Ok(true)
+    }
+
+    async fn equalizer<'ctx>(&self, ctx: &Context<'_>) -> Result<Option<EqualizerStatus>, Error> {
+        let data = ctx.data::<Storage>()?;
+        let equalizer = data.equalizer.lock().await;
+        Ok(equalizer.clone())
+    }
+
+    async fn enable_equalizer<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        enabled: bool,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::EnableEqualizer(enabled))
+            .await?;
+        Ok(true)
+    }
+
+    /// Sets every band at once, from the lowest frequency to the highest.
+    async fn set_equalizer_gains<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        gains: Vec<f64>,
+        #[graphql(default = true)] enabled: bool,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetEqualizer(EqualizerOptions {
+                enabled,
+                gains,
+                preset: None,
+            }))
+            .await?;
+        Ok(true)
+    }
+
+    async fn set_equalizer_band<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        band: i32,
+        gain: f64,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetEqualizerBand(
+                band.max(0) as usize,
+                gain,
+            ))
+            .await?;
+        Ok(true)
+    }
+
+    async fn set_equalizer_preset<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        preset: String,
+    ) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetEqualizerPreset(preset))
+            .await?;
+        Ok(true)
+    }
+
+    async fn set_repeat<'ctx>(&self, ctx: &Context<'_>, repeat: RepeatMode) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SetRepeat(repeat))
+            .await?;
+        Ok(true)
+    }
+
     async fn pause<'ctx>(&self, ctx: &Context<'_>) -> Result<bool, Error> {
         let data = ctx.data::<Storage>()?;
         let mut to_player_msg = data.to_player_message.lock().await;
@@ -131,6 +717,72 @@ impl QueryRoot {
         to_player_msg.send(ToPlayerMessages::Seek(seconds)).await?;
         Ok(true)
     }
+
+    async fn seek_to<'ctx>(&self, ctx: &Context<'_>, seconds: f64) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SeekTo(seconds))
+            .await?;
+        Ok(true)
+    }
+
+    async fn seek_percent<'ctx>(&self, ctx: &Context<'_>, percent: f64) -> Result<bool, Error> {
+        let data = ctx.data::<Storage>()?;
+        let mut to_player_msg = data.to_player_message.lock().await;
+        to_player_msg
+            .send(ToPlayerMessages::SeekPercent(percent))
+            .await?;
+        Ok(true)
+    }
+}
+
+fn open_cache() -> Result<CacheManager, Error> {
+    CacheManager::open().ok_or_else(|| Error::new("No audio directory for the cache"))
+}
+
+/// Builds the options for playing `url`, finding its length from the server or the cached file.
+async fn play_options(
+    video_id: String,
+    title: Option<String>,
+    url: String,
+    file_path: Option<String>,
+) -> PlayOptions {
+    log::info!("Get length");
+    let mut length = {
+        if let Ok(url) = surf::Url::parse(&url) {
+            let response = crate::network::client().get(&url).send().await;
+            response.ok().and_then(|r| r.len())
+        } else {
+            None
+        }
+    };
+
+    if length.is_none() {
+        if let Some(file_path) = &file_path {
+            // A partial cache file is shorter than the track, only its index knows the length.
+            match RangeIndex::load(file_path) {
+                Some(index) if index.content_length != 0 => length = Some(index.content_length),
+                Some(_) => {}
+                None if RangeIndex::sidecar_path(file_path).exists() => {}
+                None => {
+                    if let Ok(file) = async_std::fs::File::open(&file_path).await {
+                        if let Ok(metadata) = file.metadata().await {
+                            length = Some(metadata.len() as usize);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    PlayOptions {
+        video_id,
+        title,
+        url,
+        length,
+        file_path,
+    }
 }
 
 pub struct SubscriptionRoot {}
@@ -4,7 +4,7 @@ use async_trait::async_trait;
 use rusty_pipe::{downloader_trait::Downloader, youtube_extractor::error::ParsingError};
 use surf::Client;
 lazy_static::lazy_static! {
-    static ref SURF_CLIENT:surf::Client = surf::Client::new();
+    static ref SURF_CLIENT:surf::Client = crate::network::client();
 }
 
 #[derive(Clone)]
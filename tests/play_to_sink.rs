@@ -0,0 +1,168 @@
+//! Plays a WAV file served from localhost through the player into a WAV sink, and checks that the
+//! sink holds the samples of the file.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use rusty_pipe_cli::{PlayOptions, PlayerMessage, PlayerState, Sink, ToPlayerMessages};
+
+const RATE: u32 = 22050;
+/// Frames in the fixture, half a second.
+const FRAMES: usize = RATE as usize / 2;
+
+fn sample_at(frame: usize) -> i16 {
+    let t = frame as f32 / RATE as f32;
+    ((2.0 * std::f32::consts::PI * 440.0 * t).sin() * 0.5 * i16::MAX as f32) as i16
+}
+
+/// A mono 16 bit WAV file holding a 440 Hz sine.
+fn fixture() -> Vec<u8> {
+    let data_len = (FRAMES * 2) as u32;
+    let mut wav = vec![];
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&RATE.to_le_bytes());
+    wav.extend_from_slice(&(RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for frame in 0..FRAMES {
+        wav.extend_from_slice(&sample_at(frame).to_le_bytes());
+    }
+    wav
+}
+
+/// Serves `file` at every path, honouring `Range: bytes=<start>-[<end>]`.
+fn serve(listener: TcpListener, file: Arc<Vec<u8>>) {
+    for stream in listener.incoming().flatten() {
+        let file = file.clone();
+        std::thread::spawn(move || {
+            // The client hangs up once it read what it wanted.
+            let _ = respond(stream, &file);
+        });
+    }
+}
+
+fn respond(mut stream: TcpStream, file: &[u8]) -> std::io::Result<()> {
+    let mut range = None;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let lower = line.to_lowercase();
+        if let Some(value) = lower.strip_prefix("range: bytes=") {
+            let (start, end) = value.trim().split_once('-').unwrap_or((value.trim(), ""));
+            let start = start.parse::<usize>().unwrap_or(0);
+            let end = end.parse::<usize>().map_or(file.len(), |end| end + 1);
+            range = Some((start, end.min(file.len())));
+        }
+    }
+    let (status, start, end) = match range {
+        Some((start, _)) if start >= file.len() => ("416 Range Not Satisfiable", 0, 0),
+        Some((start, end)) => ("206 Partial Content", start, end),
+        None => ("200 OK", 0, file.len()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        end - start
+    )?;
+    stream.write_all(&file[start..end])
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rusty_pipe_{}_{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).expect("Cant create temp dir");
+    dir
+}
+
+/// Reads the 32 bit float samples of the WAV file the sink wrote to `path`.
+fn sink_samples(path: &Path) -> Vec<f32> {
+    let data = std::fs::read(path).expect("Cant read sink file");
+    assert_eq!(&data[0..4], b"RIFF");
+    let data_len = u32::from_le_bytes([data[40], data[41], data[42], data[43]]) as usize;
+    assert_eq!(data_len, data.len() - 44);
+    data[44..]
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+#[test]
+fn plays_a_track_into_a_wav_sink() {
+    // Keep the configuration and the cache of the user out of the test.
+    let home = temp_dir("home");
+    std::env::set_var("HOME", &home);
+    std::env::set_var("XDG_CONFIG_HOME", home.join("config"));
+    std::env::set_var("XDG_CACHE_HOME", home.join("cache"));
+
+    let file = Arc::new(fixture());
+    let length = file.len();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Cant bind fixture server");
+    let address = listener.local_addr().expect("No address").to_string();
+    std::thread::spawn(move || serve(listener, file));
+
+    let output = home.join("out.wav");
+    let (mut to_player, player_rx) = futures::channel::mpsc::channel(2);
+    let (player_tx, mut from_player) = futures::channel::mpsc::channel(2);
+    let sink = Sink::Wav(output.clone());
+    std::thread::spawn(move || {
+        async_std::task::block_on(rusty_pipe_cli::r_player::run_audio_player(
+            player_rx,
+            player_tx,
+            Some(sink),
+        ))
+    });
+
+    async_std::task::block_on(async {
+        to_player
+            .send(ToPlayerMessages::Play(PlayOptions {
+                video_id: "fixture".to_string(),
+                title: None,
+                url: format!("http://{}/videoplayback?id=fixture", address),
+                length: Some(length),
+                file_path: None,
+            }))
+            .await
+            .expect("Cant send to player");
+        let ended = async {
+            while let Some(msg) = from_player.next().await {
+                match msg {
+                    PlayerMessage::State(status) if status.state == PlayerState::Ended => return,
+                    PlayerMessage::Error(err) => panic!("Player failed {}", err.message),
+                    _ => {}
+                }
+            }
+            panic!("Player stopped");
+        };
+        async_std::future::timeout(Duration::from_secs(30), ended)
+            .await
+            .expect("Track did not end");
+    });
+
+    let samples = sink_samples(&output);
+    assert_eq!(samples.len(), FRAMES);
+    for (frame, sample) in samples.iter().enumerate() {
+        let expected = sample_at(frame) as f32 / 32768.0;
+        assert!(
+            (sample - expected).abs() < 1e-4,
+            "Sample {} is {}, expected {}",
+            frame,
+            sample,
+            expected
+        );
+    }
+    let _ = std::fs::remove_dir_all(&home);
+}